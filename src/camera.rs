@@ -1,7 +1,11 @@
 /* Quite complex actually; define coordinate systems etc and ray generation */
 #![allow(dead_code)]
 
+use std::path::Path;
 use super::vector::*;
+use super::sampler::Sampler;
+use super::transform::{Motion, Transform};
+use super::camera_model::CameraModel;
 
 /// From L the direction a camera points and G the global up,
 /// generate the orthonormal basis (L, S, U) where S points to
@@ -14,8 +18,16 @@ fn directions(looking: Vec3, global_up: Vec3) -> (Vec3, Vec3) {
 
 /// A Camera encapsulates ray generation (and usually holds basis vectors etc)
 pub trait Camera {
-    fn generate_ray(&self, x: usize, y: usize, width: usize, height: usize) -> Ray;
+    /// Ray through a point on the film; pixel (x, y) covers [x, x + 1) by [y, y + 1)
+    fn generate_ray_at(&self, film_x: Float, film_y: Float, width: usize, height: usize, sampler: &mut dyn Sampler) -> Ray;
     fn translate(&mut self, direction: Vec3);
+
+    /// Ray through a random point in the pixel
+    fn generate_ray(&self, x: usize, y: usize, width: usize, height: usize, sampler: &mut dyn Sampler) -> Ray {
+        let film_x = x as Float + random_float(sampler);
+        let film_y = y as Float + random_float(sampler);
+        self.generate_ray_at(film_x, film_y, width, height, sampler)
+    }
 }
 
 /// A camera with no DOF etc
@@ -42,18 +54,23 @@ impl SimpleCamera {
 }
 
 impl Camera for SimpleCamera {
-    fn generate_ray(&self, x: usize, y: usize, width: usize, height: usize) -> Ray {
+    /// Always through the corner of the pixel
+    fn generate_ray(&self, x: usize, y: usize, width: usize, height: usize, sampler: &mut dyn Sampler) -> Ray {
+        self.generate_ray_at(x as Float, y as Float, width, height, sampler)
+    }
+
+    fn generate_ray_at(&self, film_x: Float, film_y: Float, width: usize, height: usize, _sampler: &mut dyn Sampler) -> Ray {
         //let x_bar = 1. - (2*x) as Float/width as Float;
         //let y_bar = (height as Float - 2. * y as Float)/width as Float;
 
         //Ray {origin: self.position,
         //    direction: (self.looking + self.tan_half_fov * (x_bar * self.side + y_bar * self.up)).normalise()}
             
-        let x_bar = width as Float - (2 * x) as Float;
-        let y_bar = height as Float - (2 * y) as Float;
+        let x_bar = width as Float - 2. * film_x;
+        let y_bar = height as Float - 2. * film_y;
 
         Ray {origin: self.position,
-            direction: (width as Float*self.looking + self.tan_half_fov*(x_bar*self.side + y_bar*self.up)).normalise()}
+            direction: (width as Float*self.looking + self.tan_half_fov*(x_bar*self.side + y_bar*self.up)).normalise(), time: 0.}
     
     }
 
@@ -91,21 +108,19 @@ impl SimpleAACamera {
 }
 
 impl Camera for SimpleAACamera {
-    fn generate_ray(&self, x: usize, y: usize, width: usize, height: usize) -> Ray {
+    fn generate_ray_at(&self, film_x: Float, film_y: Float, width: usize, height: usize, _sampler: &mut dyn Sampler) -> Ray {
         //let x_bar = 1. - (2*x) as Float/width as Float;
         //let y_bar = (height as Float - 2. * y as Float)/width as Float;
 
         //Ray {origin: self.position,
         //    direction: (self.looking + self.tan_half_fov * (x_bar * self.side + y_bar * self.up)).normalise()}
             
-        //let x_bar = width as Float - (2 * x) as Float;
-        let x_bar = width as Float - 2. * (x as Float + random_float());
+        let x_bar = width as Float - 2. * film_x;
 
-        //let y_bar = height as Float - (2 * y) as Float;
-        let y_bar = height as Float - 2. * (y as Float + random_float());
+        let y_bar = height as Float - 2. * film_y;
 
         Ray {origin: self.position,
-            direction: (width as Float*self.looking + self.tan_half_fov*(x_bar*self.side + y_bar*self.up)).normalise()}
+            direction: (width as Float*self.looking + self.tan_half_fov*(x_bar*self.side + y_bar*self.up)).normalise(), time: 0.}
     
     }
 
@@ -119,11 +134,84 @@ impl Camera for SimpleAACamera {
 
 
 
+/// Grey values of an image, for sampling an aperture with the shape (and transmission) of it
+pub struct ApertureMask {
+    width: usize,
+    height: usize,
+    cdf: Vec<Float>, // Running total of the pixel values, normalised to end at 1
+}
+
+impl ApertureMask {
+    pub fn load(path: &Path) -> Result<ApertureMask, String> {
+        let image = image::open(path).map_err(|e| e.to_string())?.to_luma8();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        ApertureMask::new(width, height, image.pixels().map(|pixel| pixel.0[0] as Float / 255.).collect())
+    }
+
+    /// From the values row by row, top row first
+    pub fn new(width: usize, height: usize, values: Vec<Float>) -> Result<ApertureMask, String> {
+        let mut total = 0.;
+        let mut cdf: Vec<Float> = values.iter().map(|value| { total += value.max(0.); total }).collect();
+        if total <= 0. {
+            return Err("Aperture mask is completely black".to_string());
+        }
+        cdf.iter_mut().for_each(|value| *value /= total);
+
+        Ok(ApertureMask { width, height, cdf })
+    }
+
+    /// A point in [-1, 1] x [-1, 1], with up positive
+    fn sample(&self, u: Float, v: Float, w: Float) -> (Float, Float) {
+        let i = self.cdf.partition_point(|&value| value < u).min(self.cdf.len() - 1);
+        let (x, y) = (i % self.width, i / self.width);
+
+        let scale = 2. / self.width.max(self.height) as Float;
+        (scale * (x as Float + v - 0.5 * self.width as Float), scale * (0.5 * self.height as Float - y as Float - w))
+    }
+}
+
+pub enum ApertureShape {
+    Circle,
+    Polygon { blades: usize, rotation: Float }, // Regular polygon with its corners on the circle
+    Mask(ApertureMask),
+}
+
+impl ApertureShape {
+    /// A point on the unit aperture, as (side, up)
+    fn sample(&self, sampler: &mut dyn Sampler) -> (Float, Float) {
+        match self {
+            ApertureShape::Circle => {
+                let angle = random_float(sampler) * 2. * PI;
+                let r = random_float(sampler).sqrt();
+                (r * angle.sin(), r * angle.cos())
+            },
+            ApertureShape::Polygon { blades, rotation } => {
+                // One of the triangles between the centre and each edge, then a point in it
+                let blades = (*blades).max(3);
+                let scaled = random_float(sampler) * blades as Float;
+                let blade = (scaled as usize).min(blades - 1);
+                let step = 2. * PI / blades as Float;
+                let (a, b) = (rotation + blade as Float * step, rotation + (blade + 1) as Float * step);
+
+                let r = random_float(sampler).sqrt();
+                let t = scaled - blade as Float;
+                let (x, y) = ((1. - t) * a.sin() + t * b.sin(), (1. - t) * a.cos() + t * b.cos());
+                (r * x, r * y)
+            },
+            ApertureShape::Mask(mask) => {
+                let (u, v, w) = (random_float(sampler), random_float(sampler), random_float(sampler));
+                mask.sample(u, v, w)
+            },
+        }
+    }
+}
+
 /// A camera with DOF and AA
 /// The FOV is horizontal
-/// Aperture is also perfectly -circular- square (???)
-/// Probably requires more thought
-/// Focal plane is flat for some reason
+/// The aperture shape gives the bokeh; squeeze narrows it sideways like an anamorphic lens
+/// (2 for the tall ovals of a 2x anamorphic)
+/// Shift moves the film to correct perspective without turning the camera; tilt turns the plane
+/// of focus (Scheimpflug) for miniature shots, and curvature bends it into a sphere round the lens
 pub struct DOFCamera {
     pub tan_half_fov: Float,
     pub position: Vec3,
@@ -132,6 +220,11 @@ pub struct DOFCamera {
     pub up: Vec3,
     pub focal_distance: Float,
     pub aperture: Float, // Some generic measure of focal distance
+    pub aperture_shape: ApertureShape,
+    pub squeeze: Float,
+    pub shift: (Float, Float), // Film moved right and up, as tangents like tan_half_fov
+    pub tilt: (Float, Float), // Plane of focus turned so its right and top are further away, in radians
+    pub curvature: Float, // 0 for a flat focal surface, 1 for a sphere focal_distance from the lens
 }
 
 impl DOFCamera {
@@ -145,31 +238,51 @@ impl DOFCamera {
             up: up,
             focal_distance: focal_distance,
             aperture: aperture,
+            aperture_shape: ApertureShape::Circle,
+            squeeze: 1.,
+            shift: (0., 0.),
+            tilt: (0., 0.),
+            curvature: 0.,
         }
     }
+
+    /// The camera described by the model, for an image of this size
+    pub fn from_model(model: &CameraModel, width: usize, height: usize) -> DOFCamera {
+        let (tan_half_width, _) = model.tan_half_extents(width, height);
+        DOFCamera::new(2. * tan_half_width.atan(), model.position, model.looking, model.global_up,
+                       model.focus_distance, model.aperture)
+    }
 }
 
 impl Camera for DOFCamera {
-    fn generate_ray(&self, x: usize, y: usize, width: usize, height: usize) -> Ray {
+    fn generate_ray_at(&self, film_x: Float, film_y: Float, width: usize, height: usize, sampler: &mut dyn Sampler) -> Ray {
         //let x_bar = 1. - (2*x) as Float/width as Float;
         //let y_bar = (height as Float - 2. * y as Float)/width as Float;
 
         //Ray {origin: self.position,
         //    direction: (self.looking + self.tan_half_fov * (x_bar * self.side + y_bar * self.up)).normalise()}
             
-        let x_bar = width as Float - 2. * (x as Float + random_float());
+        let x_bar = width as Float - 2. * film_x;
 
-        let y_bar = height as Float - 2. * (y as Float + random_float());
+        let y_bar = height as Float - 2. * film_y;
 
-        let scaled_direction = self.focal_distance / width as Float * (width as Float*self.looking + self.tan_half_fov*(x_bar*self.side + y_bar*self.up));
+        let direction = (1. / width as Float) * (width as Float*self.looking + self.tan_half_fov*(x_bar*self.side + y_bar*self.up))
+            - self.shift.0 * self.side + self.shift.1 * self.up;
+
+        // How far along the direction the focal surface is; the plane goes through the point
+        // focal_distance ahead whichever way it is tilted
+        let normal = self.looking + self.tilt.0.tan() * self.side - self.tilt.1.tan() * self.up;
+        let flat = self.focal_distance * dot(self.looking, normal) / dot(direction, normal).max(EPS);
+        let curved = self.focal_distance / direction.norm();
+        let scaled_direction = ((1. - self.curvature) * flat + self.curvature * curved) * direction;
 
         //let random_position = self.aperture * ((2. * random_float() - 1.) * self.side + (2. * random_float() - 1.) * self.up);
 
-        let angle = random_float() * 2. * PI;
-        let random_position = self.aperture * random_float().sqrt() * (angle.sin() * self.side + angle.cos() * self.up);
+        let (x, y) = self.aperture_shape.sample(sampler);
+        let random_position = self.aperture * ((x / self.squeeze) * self.side + y * self.up);
 
         Ray {origin: self.position + random_position,
-            direction: (scaled_direction - random_position).normalise()}
+            direction: (scaled_direction - random_position).normalise(), time: 0.}
     
     }
 
@@ -179,4 +292,416 @@ impl Camera for DOFCamera {
             + direction.y * self.side
             + direction.z * self.up;
     }
- }
\ No newline at end of file
+ }
+
+
+
+/// A camera with parallel rays, for technical drawings
+/// The view width is the size of the image horizontally in world units
+pub struct OrthographicCamera {
+    pub view_width: Float,
+    pub position: Vec3,
+    pub looking: Vec3,
+    pub side: Vec3,
+    pub up: Vec3,
+}
+
+impl OrthographicCamera {
+    pub fn new(view_width: Float, position: Vec3, looking: Vec3, global_up: Vec3) -> OrthographicCamera {
+        let (side, up) = directions(looking, global_up);
+        OrthographicCamera {
+            view_width: view_width,
+            position: position,
+            looking: looking,
+            side: side,
+            up: up,
+        }
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn generate_ray_at(&self, film_x: Float, film_y: Float, width: usize, height: usize, _sampler: &mut dyn Sampler) -> Ray {
+        let x_bar = width as Float - 2. * film_x;
+        let y_bar = height as Float - 2. * film_y;
+
+        let scale = self.view_width / (2. * width as Float);
+
+        Ray {origin: self.position + scale * (x_bar * self.side + y_bar * self.up),
+            direction: self.looking, time: 0.}
+    }
+
+
+    fn translate(&mut self, direction: Vec3) {
+        self.position += direction.x * self.looking
+            + direction.y * self.side
+            + direction.z * self.up;
+    }
+}
+
+
+
+/// A full 360 by 180 degree latitude-longitude panorama, the usual layout for environment maps
+/// The looking direction is in the middle of the image
+pub struct EquirectangularCamera {
+    pub position: Vec3,
+    pub looking: Vec3,
+    pub side: Vec3,
+    pub up: Vec3,
+}
+
+impl EquirectangularCamera {
+    pub fn new(position: Vec3, looking: Vec3, global_up: Vec3) -> EquirectangularCamera {
+        let (side, up) = directions(looking, global_up);
+        EquirectangularCamera {
+            position: position,
+            looking: looking,
+            side: side,
+            up: up,
+        }
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn generate_ray_at(&self, film_x: Float, film_y: Float, width: usize, height: usize, _sampler: &mut dyn Sampler) -> Ray {
+        // Longitude increases to the left like the side vector, latitude from the top down
+        let phi = PI * (1. - 2. * film_x / width as Float);
+        let theta = PI * film_y / height as Float;
+
+        Ray {origin: self.position,
+            direction: theta.sin() * (phi.cos() * self.looking + phi.sin() * self.side) + theta.cos() * self.up, time: 0.}
+    }
+
+
+    fn translate(&mut self, direction: Vec3) {
+        self.position += direction.x * self.looking
+            + direction.y * self.side
+            + direction.z * self.up;
+    }
+}
+
+
+
+#[derive(Copy, Clone)]
+pub enum CubeFace {
+    PositiveX,
+    NegativeX,
+    PositiveY,
+    NegativeY,
+    PositiveZ,
+    NegativeZ,
+}
+
+impl CubeFace {
+    pub const ALL: [CubeFace; 6] = [CubeFace::PositiveX, CubeFace::NegativeX, CubeFace::PositiveY,
+                                    CubeFace::NegativeY, CubeFace::PositiveZ, CubeFace::NegativeZ];
+
+    /// Looking direction and up; the side faces have Y up, the top and bottom have -Z and Z
+    pub fn basis(&self) -> (Vec3, Vec3) {
+        match self {
+            CubeFace::PositiveX => (Vec3::X, Vec3::Y),
+            CubeFace::NegativeX => (-Vec3::X, Vec3::Y),
+            CubeFace::PositiveY => (Vec3::Y, -Vec3::Z),
+            CubeFace::NegativeY => (-Vec3::Y, Vec3::Z),
+            CubeFace::PositiveZ => (Vec3::Z, Vec3::Y),
+            CubeFace::NegativeZ => (-Vec3::Z, Vec3::Y),
+        }
+    }
+}
+
+/// One face of a cube map around a point; 90 degrees each way, so the image should be square
+pub struct CubeFaceCamera {
+    pub face: CubeFace,
+    pub position: Vec3,
+    pub looking: Vec3,
+    pub side: Vec3,
+    pub up: Vec3,
+}
+
+impl CubeFaceCamera {
+    pub fn new(face: CubeFace, position: Vec3) -> CubeFaceCamera {
+        let (looking, global_up) = face.basis();
+        let (side, up) = directions(looking, global_up);
+        CubeFaceCamera {
+            face: face,
+            position: position,
+            looking: looking,
+            side: side,
+            up: up,
+        }
+    }
+}
+
+impl Camera for CubeFaceCamera {
+    fn generate_ray_at(&self, film_x: Float, film_y: Float, width: usize, height: usize, _sampler: &mut dyn Sampler) -> Ray {
+        let x_bar = 1. - 2. * film_x / width as Float;
+        let y_bar = 1. - 2. * film_y / height as Float;
+
+        Ray {origin: self.position,
+            direction: (self.looking + x_bar * self.side + y_bar * self.up).normalise(), time: 0.}
+    }
+
+
+    fn translate(&mut self, direction: Vec3) {
+        self.position += direction.x * self.looking
+            + direction.y * self.side
+            + direction.z * self.up;
+    }
+}
+
+
+
+#[derive(Copy, Clone)]
+pub enum FisheyeMapping {
+    Equidistant, // Distance from the centre proportional to the angle
+    Equisolid, // Area proportional to solid angle
+}
+
+/// A circular fisheye filling the shorter side of the image, for domes
+/// The FOV is the full angle across the circle; film outside the circle gets rays with no
+/// direction, which come back black
+pub struct FisheyeCamera {
+    pub fov: Float,
+    pub mapping: FisheyeMapping,
+    pub position: Vec3,
+    pub looking: Vec3,
+    pub side: Vec3,
+    pub up: Vec3,
+}
+
+impl FisheyeCamera {
+    pub fn new(fov: Float, mapping: FisheyeMapping, position: Vec3, looking: Vec3, global_up: Vec3) -> FisheyeCamera {
+        let (side, up) = directions(looking, global_up);
+        FisheyeCamera {
+            fov: fov,
+            mapping: mapping,
+            position: position,
+            looking: looking,
+            side: side,
+            up: up,
+        }
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn generate_ray_at(&self, film_x: Float, film_y: Float, width: usize, height: usize, _sampler: &mut dyn Sampler) -> Ray {
+        let size = width.min(height) as Float;
+        let x_bar = (width as Float - 2. * film_x) / size;
+        let y_bar = (height as Float - 2. * film_y) / size;
+
+        let r = (x_bar * x_bar + y_bar * y_bar).sqrt();
+        if r > 1. {
+            return Ray {origin: self.position, direction: Vec3::ZERO, time: 0.};
+        }
+
+        let theta = match self.mapping {
+            FisheyeMapping::Equidistant => r * self.fov / 2.,
+            FisheyeMapping::Equisolid => 2. * (r * (self.fov / 4.).sin()).min(1.).asin(),
+        };
+
+        // Straight ahead in the middle of the circle
+        let (cos_phi, sin_phi) = if r < EPS { (1., 0.) } else { (x_bar / r, y_bar / r) };
+
+        Ray {origin: self.position,
+            direction: theta.cos() * self.looking + theta.sin() * (cos_phi * self.side + sin_phi * self.up), time: 0.}
+    }
+
+
+    fn translate(&mut self, direction: Vec3) {
+        self.position += direction.x * self.looking
+            + direction.y * self.side
+            + direction.z * self.up;
+    }
+}
+
+
+
+/// Another camera with a shutter, so rays are spread over the time it is open, and maybe moving
+/// too; the motion turns and moves the camera about the pivot, which is usually its position
+/// Translating only moves the inner camera, not the pivot
+pub struct MovingCamera<C: Camera> {
+    pub camera: C,
+    pub open: Float,
+    pub close: Float,
+    pub motion: Motion,
+    pub pivot: Vec3,
+}
+
+impl<C: Camera> MovingCamera<C> {
+    /// Just the shutter; for when only the objects move
+    pub fn still(camera: C, open: Float, close: Float) -> MovingCamera<C> {
+        MovingCamera {
+            camera: camera,
+            open: open,
+            close: close,
+            motion: Motion::Linear { start: Transform::IDENTITY, end: Transform::IDENTITY },
+            pivot: Vec3::ZERO,
+        }
+    }
+}
+
+impl<C: Camera> Camera for MovingCamera<C> {
+    fn generate_ray_at(&self, film_x: Float, film_y: Float, width: usize, height: usize, sampler: &mut dyn Sampler) -> Ray {
+        let time = self.open + random_float(sampler) * (self.close - self.open);
+        let ray = self.camera.generate_ray_at(film_x, film_y, width, height, sampler);
+
+        let transform = self.motion.at(time);
+        Ray {origin: transform.point(ray.origin - self.pivot) + self.pivot,
+            direction: transform.normal(ray.direction), time: time}
+    }
+
+
+    fn translate(&mut self, direction: Vec3) {
+        self.camera.translate(direction);
+    }
+}
+
+
+
+#[derive(Copy, Clone)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+/// How both eyes share one image; the left eye goes on the left or on top.
+/// Red-cyan anaglyphs need a film per eye, see Film::anaglyph
+#[derive(Copy, Clone)]
+pub enum StereoLayout {
+    SideBySide,
+    TopBottom,
+}
+
+/// Two eyes interocular apart either side of the model's position, with zero parallax at the
+/// convergence distance. The eyes look the same way and shift their films (off-axis) rather
+/// than turning in, so there's no vertical parallax at the edges
+pub struct StereoRig {
+    pub model: CameraModel,
+    pub interocular: Float,
+    pub convergence: Float,
+}
+
+impl StereoRig {
+    pub fn new(model: CameraModel, interocular: Float, convergence: Float) -> StereoRig {
+        StereoRig {
+            model: model,
+            interocular: interocular,
+            convergence: convergence,
+        }
+    }
+
+    /// One eye's camera for an image of this size
+    pub fn eye(&self, eye: Eye, width: usize, height: usize) -> DOFCamera {
+        let (side, _) = self.model.basis();
+        // Side points left, so the left eye is along it
+        let offset = match eye { Eye::Left => 0.5 * self.interocular, Eye::Right => -0.5 * self.interocular };
+
+        let mut camera = DOFCamera::from_model(&self.model, width, height);
+        camera.position += offset * side;
+        camera.shift.0 = offset / self.convergence;
+        camera
+    }
+
+    /// Both eyes in one image of this size
+    pub fn camera(&self, layout: StereoLayout, width: usize, height: usize) -> StereoCamera<DOFCamera> {
+        let (eye_width, eye_height) = match layout {
+            StereoLayout::SideBySide => (width / 2, height),
+            StereoLayout::TopBottom => (width, height / 2),
+        };
+        StereoCamera {
+            left: self.eye(Eye::Left, eye_width, eye_height),
+            right: self.eye(Eye::Right, eye_width, eye_height),
+            layout: layout,
+        }
+    }
+
+    /// Omni-directional stereo panoramas for both eyes, usually top and bottom
+    pub fn ods(&self, layout: StereoLayout) -> StereoCamera<OdsCamera> {
+        StereoCamera {
+            left: OdsCamera::new(Eye::Left, self.interocular, self.model.position, self.model.looking, self.model.global_up),
+            right: OdsCamera::new(Eye::Right, self.interocular, self.model.position, self.model.looking, self.model.global_up),
+            layout: layout,
+        }
+    }
+}
+
+/// A camera per eye, each given its own part of the image
+pub struct StereoCamera<C: Camera> {
+    pub left: C,
+    pub right: C,
+    pub layout: StereoLayout,
+}
+
+impl<C: Camera> Camera for StereoCamera<C> {
+    fn generate_ray_at(&self, film_x: Float, film_y: Float, width: usize, height: usize, sampler: &mut dyn Sampler) -> Ray {
+        match self.layout {
+            StereoLayout::SideBySide => {
+                let half = width / 2;
+                if film_x < half as Float {
+                    self.left.generate_ray_at(film_x, film_y, half, height, sampler)
+                } else {
+                    self.right.generate_ray_at(film_x - half as Float, film_y, width - half, height, sampler)
+                }
+            },
+            StereoLayout::TopBottom => {
+                let half = height / 2;
+                if film_y < half as Float {
+                    self.left.generate_ray_at(film_x, film_y, width, half, sampler)
+                } else {
+                    self.right.generate_ray_at(film_x, film_y - half as Float, width, height - half, sampler)
+                }
+            },
+        }
+    }
+
+
+    fn translate(&mut self, direction: Vec3) {
+        self.left.translate(direction);
+        self.right.translate(direction);
+    }
+}
+
+/// One eye of an omni-directional stereo panorama: an equirectangular image where every ray
+/// starts on a circle interocular wide, to the side of its direction the eye would be on
+/// looking that way
+pub struct OdsCamera {
+    pub offset: Float, // Radius of the circle; negative for the right eye
+    pub position: Vec3,
+    pub looking: Vec3,
+    pub side: Vec3,
+    pub up: Vec3,
+}
+
+impl OdsCamera {
+    pub fn new(eye: Eye, interocular: Float, position: Vec3, looking: Vec3, global_up: Vec3) -> OdsCamera {
+        let (side, up) = directions(looking, global_up);
+        OdsCamera {
+            offset: match eye { Eye::Left => 0.5 * interocular, Eye::Right => -0.5 * interocular },
+            position: position,
+            looking: looking,
+            side: side,
+            up: up,
+        }
+    }
+}
+
+impl Camera for OdsCamera {
+    fn generate_ray_at(&self, film_x: Float, film_y: Float, width: usize, height: usize, _sampler: &mut dyn Sampler) -> Ray {
+        // As the equirectangular camera
+        let phi = PI * (1. - 2. * film_x / width as Float);
+        let theta = PI * film_y / height as Float;
+
+        // The horizontal direction, and the one to the left of it
+        let across = phi.cos() * self.looking + phi.sin() * self.side;
+        let left = phi.cos() * self.side - phi.sin() * self.looking;
+
+        Ray {origin: self.position + self.offset * left,
+            direction: theta.sin() * across + theta.cos() * self.up, time: 0.}
+    }
+
+
+    fn translate(&mut self, direction: Vec3) {
+        self.position += direction.x * self.looking
+            + direction.y * self.side
+            + direction.z * self.up;
+    }
+}
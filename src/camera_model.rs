@@ -0,0 +1,72 @@
+/* A description of a camera that both renderers understand, so a scene frames the same in each.
+   The CPU turns it into a DOFCamera and the GPU viewer pushes it to pt.comp as uniforms */
+#![allow(dead_code)]
+use super::vector::*;
+
+/// Field of view as a full angle in radians, either across or up the image; the other one
+/// follows from the aspect ratio
+#[derive(Copy, Clone)]
+pub enum Fov {
+    Horizontal(Float),
+    Vertical(Float),
+}
+
+#[derive(Copy, Clone)]
+pub struct CameraModel {
+    pub position: Vec3,
+    pub looking: Vec3, // Unit length
+    pub global_up: Vec3,
+    pub fov: Fov,
+    pub focus_distance: Float, // Distance along looking of the plane in focus
+    pub aperture: Float, // Lens radius; 0 for a pinhole
+}
+
+impl CameraModel {
+    pub fn new(position: Vec3, looking: Vec3, global_up: Vec3, fov: Fov, focus_distance: Float, aperture: Float) -> CameraModel {
+        CameraModel {
+            position,
+            looking: looking.normalise(),
+            global_up,
+            fov,
+            focus_distance,
+            aperture,
+        }
+    }
+
+    /// Pointed at the target, which is also in focus
+    pub fn look_at(position: Vec3, target: Vec3, global_up: Vec3, fov: Fov, aperture: Float) -> CameraModel {
+        let offset = target - position;
+        CameraModel::new(position, offset, global_up, fov, offset.norm(), aperture)
+    }
+
+    /// The side (to the left of the image) and up vectors, as the CPU cameras and pt.comp make them
+    pub fn basis(&self) -> (Vec3, Vec3) {
+        let side = cross(self.global_up, self.looking).normalise();
+        (side, cross(self.looking, side))
+    }
+
+    /// Tangents of half the FOV across and up the image
+    pub fn tan_half_extents(&self, width: usize, height: usize) -> (Float, Float) {
+        let aspect = width as Float / height as Float;
+        match self.fov {
+            Fov::Horizontal(angle) => {
+                let across = (angle / 2.).tan();
+                (across, across / aspect)
+            },
+            Fov::Vertical(angle) => {
+                let up = (angle / 2.).tan();
+                (up * aspect, up)
+            },
+        }
+    }
+
+    /// Yaw (about Y, 0 looking down -Z) and pitch (positive looking down), as the viewer steers
+    pub fn angles(&self) -> (Float, Float) {
+        (self.looking.x.atan2(-self.looking.z), -self.looking.y.clamp(-1., 1.).asin())
+    }
+
+    /// The looking direction for the viewer's angles
+    pub fn from_angles(yaw: Float, pitch: Float) -> Vec3 {
+        Vec3::new(yaw.sin() * pitch.cos(), -pitch.sin(), -yaw.cos() * pitch.cos())
+    }
+}
@@ -0,0 +1,129 @@
+/* Camera keyframes recorded in the viewer and played back for offline flythroughs.
+   Saved as text, one keyframe a line: time, position, yaw, pitch, focus distance, aperture */
+#![allow(dead_code)]
+use std::fs;
+use std::path::Path;
+use super::vector::*;
+use super::transform::Quaternion;
+use super::camera_model::{CameraModel, Fov};
+
+/// The viewer's camera at a time in seconds; the angles are as CameraModel::angles
+#[derive(Copy, Clone)]
+pub struct Keyframe {
+    pub time: Float,
+    pub position: Vec3,
+    pub yaw: Float,
+    pub pitch: Float,
+    pub focus_distance: Float,
+    pub aperture: Float,
+}
+
+impl Keyframe {
+    pub fn from_model(time: Float, model: &CameraModel) -> Keyframe {
+        let (yaw, pitch) = model.angles();
+        Keyframe {
+            time,
+            position: model.position,
+            yaw,
+            pitch,
+            focus_distance: model.focus_distance,
+            aperture: model.aperture,
+        }
+    }
+
+    /// Yaw about Y then pitch about the side, from looking down -Z
+    fn orientation(&self) -> Quaternion {
+        Quaternion::from_axis_angle(Vec3::Y, -self.yaw).mul(Quaternion::from_axis_angle(Vec3::X, -self.pitch))
+    }
+}
+
+/// Keyframes in time order
+pub struct CameraPath {
+    pub keyframes: Vec<Keyframe>,
+}
+
+/// Uniform Catmull-Rom between b and c
+fn catmull_rom(a: Vec3, b: Vec3, c: Vec3, d: Vec3, t: Float) -> Vec3 {
+    let (t2, t3) = (t * t, t * t * t);
+    0.5 * ((2. * b) + t * (c - a) + t2 * (2. * a - 5. * b + 4. * c - d) + t3 * (3. * b - 3. * c + d - a))
+}
+
+impl CameraPath {
+    pub fn new() -> CameraPath {
+        CameraPath { keyframes: Vec::new() }
+    }
+
+    pub fn record(&mut self, keyframe: Keyframe) {
+        self.keyframes.push(keyframe);
+    }
+
+    /// From the first keyframe to the last
+    pub fn duration(&self) -> Float {
+        match (self.keyframes.first(), self.keyframes.last()) {
+            (Some(first), Some(last)) => last.time - first.time,
+            _ => 0.,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text: String = self.keyframes.iter().map(|k| format!("{} {} {} {} {} {} {} {}\n",
+            k.time, k.position.x, k.position.y, k.position.z, k.yaw, k.pitch, k.focus_distance, k.aperture)).collect();
+        fs::write(path, text).map_err(|e| e.to_string())
+    }
+
+    pub fn load(path: &Path) -> Result<CameraPath, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut keyframes = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() { continue };
+            let values = line.split_whitespace().map(|value| value.parse::<Float>())
+                .collect::<Result<Vec<Float>, _>>().map_err(|e| format!("Line {}: {}", i + 1, e))?;
+            if values.len() != 8 {
+                return Err(format!("Line {}: expected 8 numbers, found {}", i + 1, values.len()));
+            }
+            if values.iter().any(|value| !value.is_finite()) {
+                return Err(format!("Line {}: numbers have to be finite", i + 1));
+            }
+
+            keyframes.push(Keyframe {
+                time: values[0],
+                position: Vec3::new(values[1], values[2], values[3]),
+                yaw: values[4],
+                pitch: values[5],
+                focus_distance: values[6],
+                aperture: values[7],
+            });
+        }
+
+        if keyframes.is_empty() {
+            return Err("No keyframes".to_string());
+        }
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        Ok(CameraPath { keyframes })
+    }
+
+    /// The camera at a time, held still before the first keyframe and after the last; None
+    /// without any keyframes.
+    /// Positions follow a Catmull-Rom spline through the keyframes, orientations slerp and
+    /// the lens settings are linear
+    pub fn at(&self, time: Float, fov: Fov) -> Option<CameraModel> {
+        let keys = &self.keyframes;
+        let last = keys.len().checked_sub(1)?;
+
+        // The segment from keys[i] to keys[i + 1]
+        let i = keys.iter().rposition(|key| key.time <= time).unwrap_or(0).min(last.saturating_sub(1));
+        let (b, c) = (keys[i], keys[(i + 1).min(last)]);
+        let (a, d) = (keys[i.saturating_sub(1)], keys[(i + 2).min(last)]);
+
+        let span = c.time - b.time;
+        let t = if span > 0. { ((time - b.time) / span).clamp(0., 1.) } else { 0. };
+
+        let position = catmull_rom(a.position, b.position, c.position, d.position, t);
+        let looking = b.orientation().slerp(c.orientation(), t).rotate(-Vec3::Z);
+        let focus_distance = b.focus_distance + t * (c.focus_distance - b.focus_distance);
+        let aperture = b.aperture + t * (c.aperture - b.aperture);
+
+        Some(CameraModel::new(position, looking, Vec3::Y, fov, focus_distance, aperture))
+    }
+}
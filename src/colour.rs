@@ -41,6 +41,11 @@ impl Colour {
     pub const WHITE: Colour = Colour {x: 1., y: 1., z: 1.};
     pub const WARN: Colour = Colour {x: 1., y: 0., z: 0.};
 
+    /// Rec. 709 luminance of a linear colour
+    pub fn luminance(self) -> Float {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
+
     /*pub fn clamp(self) -> Colour {
         Colour {x: clamp(x), y: clamp(y), z: clamp(z)}
     }*/
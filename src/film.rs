@@ -0,0 +1,222 @@
+/* The image being rendered. Samples are splatted onto every pixel the reconstruction filter
+   reaches, and each pixel also keeps statistics of its own samples to tell how noisy it still is */
+#![allow(dead_code)]
+use std::fs::File;
+use std::io::BufWriter;
+use image::{ImageBuffer, RgbImage};
+use image::codecs::hdr::HdrEncoder;
+use super::vector::*;
+use super::colour::Colour;
+
+/// Pixel reconstruction filters; separable, with the radius in pixels
+#[derive(Copy, Clone)]
+pub enum Filter {
+    Box(Float), // 0.5 is the plain average of the samples in the pixel
+    Tent(Float),
+    Gaussian { radius: Float, alpha: Float },
+    Mitchell { radius: Float, b: Float, c: Float }, // b = c = 1/3 is the recommended one
+    BlackmanHarris(Float),
+}
+
+impl Filter {
+    pub const MITCHELL: Filter = Filter::Mitchell { radius: 2., b: 1. / 3., c: 1. / 3. };
+
+    pub fn radius(&self) -> Float {
+        match *self {
+            Filter::Box(radius) | Filter::Tent(radius) | Filter::BlackmanHarris(radius) => radius,
+            Filter::Gaussian { radius, .. } | Filter::Mitchell { radius, .. } => radius,
+        }
+    }
+
+    /// Weight of a sample this far from the pixel centre
+    pub fn evaluate(&self, dx: Float, dy: Float) -> Float {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+
+    fn evaluate_1d(&self, x: Float) -> Float {
+        let x = x.abs();
+        match *self {
+            Filter::Box(radius) => if x <= radius { 1. } else { 0. },
+            Filter::Tent(radius) => (radius - x).max(0.),
+            Filter::Gaussian { radius, alpha } => {
+                // Shifted down so it reaches zero at the radius
+                ((-alpha * x * x).exp() - (-alpha * radius * radius).exp()).max(0.)
+            },
+            Filter::Mitchell { radius, b, c } => {
+                let x = 2. * x / radius;
+                if x >= 2. {
+                    0.
+                } else if x >= 1. {
+                    ((-b - 6. * c) * x * x * x + (6. * b + 30. * c) * x * x + (-12. * b - 48. * c) * x + (8. * b + 24. * c)) / 6.
+                } else {
+                    ((12. - 9. * b - 6. * c) * x * x * x + (-18. + 12. * b + 6. * c) * x * x + (6. - 2. * b)) / 6.
+                }
+            },
+            Filter::BlackmanHarris(radius) => {
+                if x >= radius { return 0. };
+                let t = 0.5 + 0.5 * x / radius;
+                0.35875 - 0.48829 * (2. * PI * t).cos() + 0.14128 * (4. * PI * t).cos() - 0.01168 * (6. * PI * t).cos()
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct FilmPixel {
+    weighted_sum: Colour, // Filtered samples, from this pixel and its neighbours
+    weight: Float,
+    half_sum: Colour, // The same for only the first half of the samples; the rest make the other half
+    half_weight: Float,
+    luminance_sum: Float, // The rest only count the pixel's own samples
+    luminance_squares: Float,
+    pub samples: usize,
+}
+
+impl FilmPixel {
+    pub const EMPTY: FilmPixel = FilmPixel {
+        weighted_sum: Colour::BLACK, weight: 0., half_sum: Colour::BLACK, half_weight: 0.,
+        luminance_sum: 0., luminance_squares: 0., samples: 0,
+    };
+
+    /// Record one of the pixel's own samples for the noise estimate
+    pub fn add(&mut self, colour: Colour) {
+        let luminance = colour.luminance();
+        self.luminance_sum += luminance;
+        self.luminance_squares += luminance * luminance;
+        self.samples += 1;
+    }
+
+    pub fn mean(&self) -> Colour {
+        if self.weight.abs() < EPS { return Colour::BLACK };
+        (1. / self.weight) * self.weighted_sum
+    }
+
+    /// The pixel as rendered by each half of the samples on their own; None until both have some
+    pub fn halves(&self) -> Option<(Colour, Colour)> {
+        let other_weight = self.weight - self.half_weight;
+        if self.half_weight.abs() < EPS || other_weight.abs() < EPS { return None };
+        Some(((1. / self.half_weight) * self.half_sum, (1. / other_weight) * (self.weighted_sum - self.half_sum)))
+    }
+
+    /// Sample variance of the luminance
+    pub fn variance(&self) -> Float {
+        if self.samples < 2 { return Float::INFINITY };
+        let n = self.samples as Float;
+        let mean = self.luminance_sum / n;
+        ((self.luminance_squares / n - mean * mean) * n / (n - 1.)).max(0.)
+    }
+
+    /// Standard error of the mean luminance relative to the mean itself; dark pixels are
+    /// measured against a floor so they don't take forever
+    pub fn relative_error(&self) -> Float {
+        let n = self.samples as Float;
+        let mean = self.luminance_sum / n.max(1.);
+        (self.variance() / n).sqrt() / mean.max(0.01)
+    }
+}
+
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    pub filter: Filter,
+    pub pixels: Vec<FilmPixel>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, filter: Filter) -> Film {
+        Film { width, height, filter, pixels: vec![FilmPixel::EMPTY; width * height] }
+    }
+
+    /// Add a sample taken at a point on the film to every pixel under the filter; the samples
+    /// should alternate between the halves
+    pub fn splat(&mut self, film_x: Float, film_y: Float, colour: Colour, first_half: bool) {
+        let radius = self.filter.radius();
+
+        // Pixel centres are at half integers
+        let x0 = (film_x - 0.5 - radius).ceil().max(0.) as usize;
+        let y0 = (film_y - 0.5 - radius).ceil().max(0.) as usize;
+        let x1 = ((film_x - 0.5 + radius).floor() as isize).min(self.width as isize - 1);
+        let y1 = ((film_y - 0.5 + radius).floor() as isize).min(self.height as isize - 1);
+
+        for y in y0 as isize..=y1 {
+            for x in x0 as isize..=x1 {
+                let weight = self.filter.evaluate(x as Float + 0.5 - film_x, y as Float + 0.5 - film_y);
+                if weight == 0. { continue };
+
+                let pixel = &mut self.pixels[x as usize + y as usize * self.width];
+                pixel.weighted_sum += weight * colour;
+                pixel.weight += weight;
+                if first_half {
+                    pixel.half_sum += weight * colour;
+                    pixel.half_weight += weight;
+                }
+            }
+        }
+    }
+
+    /// Red-cyan anaglyph of two eyes' films the same size: red from the left eye, green and blue
+    /// from the right. Only the image is kept, not the noise estimates
+    pub fn anaglyph(left: &Film, right: &Film) -> Film {
+        let pixels = left.pixels.iter().zip(right.pixels.iter()).map(|(l, r)| {
+            let (l, r) = (l.mean(), r.mean());
+            FilmPixel { weighted_sum: Colour::new(l.x, r.y, r.z), weight: 1., ..FilmPixel::EMPTY }
+        }).collect();
+
+        Film { width: left.width, height: left.height, filter: left.filter, pixels }
+    }
+
+    pub fn total_samples(&self) -> usize {
+        self.pixels.iter().map(|pixel| pixel.samples).sum()
+    }
+
+    /// Relative RMS error of the image, from how much the two half-buffers disagree. Each half
+    /// has twice the variance of the whole, so the whole is off by about half their difference.
+    /// Infinite while any pixel is missing a half, since nothing is known about it yet
+    pub fn estimated_error(&self) -> Float {
+        let mut squares = 0.;
+        let mut brightness = 0.;
+        for pixel in self.pixels.iter() {
+            let (a, b) = match pixel.halves() {
+                Some(halves) => halves,
+                None => return Float::INFINITY,
+            };
+            let difference = a.luminance() - b.luminance();
+            squares += difference * difference;
+            brightness += pixel.mean().luminance();
+        }
+
+        let n = self.pixels.len().max(1) as Float;
+        0.5 * (squares / n).sqrt() / (brightness / n).max(0.01)
+    }
+
+    /// Save as a tone mapped image, or with the raw radiance for a .hdr file (e.g. a panorama
+    /// to light another scene with)
+    pub fn save(&self, filename: &str) -> Result<(), String> {
+        if filename.ends_with(".hdr") {
+            let file = File::create(filename).map_err(|e| e.to_string())?;
+            let data: Vec<image::Rgb<f32>> = self.pixels.iter().map(|pixel| {
+                let colour = pixel.mean();
+                image::Rgb([colour.x.max(0.), colour.y.max(0.), colour.z.max(0.)])
+            }).collect();
+
+            HdrEncoder::new(BufWriter::new(file)).encode(&data, self.width, self.height).map_err(|e| e.to_string())
+        } else {
+            self.to_image().save(filename).map_err(|e| e.to_string())
+        }
+    }
+
+    pub fn to_image(&self) -> RgbImage {
+        ImageBuffer::from_fn(self.width as u32, self.height as u32, |x, y| {
+            self.pixels[x as usize + y as usize * self.width].mean().to_image_rgb()
+        })
+    }
+
+    /// Samples per pixel as a greyscale image, scaled so the busiest pixel is white
+    pub fn sample_map(&self) -> RgbImage {
+        let most = self.pixels.iter().map(|pixel| pixel.samples).max().unwrap_or(1).max(1);
+        ImageBuffer::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let value = (255 * self.pixels[x as usize + y as usize * self.width].samples / most) as u8;
+            image::Rgb([value, value, value])
+        })
+    }
+}
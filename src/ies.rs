@@ -0,0 +1,217 @@
+/* IES LM-63 photometric profiles; a candela table over vertical and horizontal angles
+   (type C photometry: vertical angles measured from the nadir) */
+#![allow(dead_code, unused_imports)]
+use std::path::Path;
+use super::vector::*;
+
+pub struct IesProfile {
+    pub vertical: Vec<Float>, // Degrees from the nadir, increasing
+    pub horizontal: Vec<Float>, // Degrees around the nadir, increasing
+    pub candela: Vec<Vec<Float>>, // One row of vertical values for each horizontal angle
+    pub flux: Float, // Integral of the intensity over all directions
+}
+
+impl IesProfile {
+    pub fn load(path: &Path) -> Result<IesProfile, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        IesProfile::parse(&text)
+    }
+
+    /// Parse the text of an IES file; the tilt data is read but ignored
+    pub fn parse(text: &str) -> Result<IesProfile, String> {
+        // Keywords come first, then a TILT line, then just numbers
+        let mut lines = text.lines();
+        let tilt = loop {
+            match lines.next() {
+                Some(line) if line.trim_start().starts_with("TILT=") => break line.trim_start()["TILT=".len()..].trim().to_string(),
+                Some(_) => continue,
+                None => return Err("No TILT line in IES file".to_string()),
+            }
+        };
+
+        let rest: Vec<&str> = lines.collect();
+        let mut numbers = rest.iter()
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|token| !token.is_empty())
+            .map(|token| token.parse::<Float>().map_err(|_| format!("Bad number in IES file: {}", token)));
+
+        let mut next = || numbers.next().unwrap_or_else(|| Err("IES file ended early".to_string()));
+
+        if tilt == "INCLUDE" {
+            // Lamp to luminaire geometry, then the angle and multiplier pairs
+            next()?;
+            let pairs = next()? as usize;
+            for _ in 0..2 * pairs {
+                next()?;
+            }
+        }
+
+        let _lamps = next()?;
+        let _lumens_per_lamp = next()?; // -1 for absolute photometry
+        let multiplier = next()?;
+        let vertical_count = next()? as usize;
+        let horizontal_count = next()? as usize;
+        let photometric_type = next()? as usize;
+        let _units = next()?;
+        let (_width, _length, _height) = (next()?, next()?, next()?);
+        let ballast_factor = next()?;
+        let _future_use = next()?;
+        let _watts = next()?;
+
+        if photometric_type != 1 {
+            return Err(format!("Only type C photometry is supported, not type {}", photometric_type));
+        }
+        if vertical_count == 0 || horizontal_count == 0 {
+            return Err("IES file has no angles".to_string());
+        }
+
+        let mut vertical = Vec::with_capacity(vertical_count);
+        for _ in 0..vertical_count {
+            vertical.push(next()?);
+        }
+
+        let mut horizontal = Vec::with_capacity(horizontal_count);
+        for _ in 0..horizontal_count {
+            horizontal.push(next()?);
+        }
+
+        let mut candela = Vec::with_capacity(horizontal_count);
+        for _ in 0..horizontal_count {
+            let mut row = Vec::with_capacity(vertical_count);
+            for _ in 0..vertical_count {
+                row.push(multiplier * ballast_factor * next()?);
+            }
+            candela.push(row);
+        }
+
+        Ok(IesProfile::new(vertical, horizontal, candela))
+    }
+
+    pub fn new(vertical: Vec<Float>, horizontal: Vec<Float>, candela: Vec<Vec<Float>>) -> IesProfile {
+        let mut profile = IesProfile { vertical, horizontal, candela, flux: 0. };
+        profile.flux = profile.integrate();
+        profile
+    }
+
+    /// A spot light as a profile; full intensity out to the inner angle, fading to nothing
+    /// at the outer one (degrees from the nadir)
+    pub fn spot(inner: Float, outer: Float) -> IesProfile {
+        let vertical: Vec<Float> = (0..=180).map(|angle| angle as Float).collect();
+        let row = vertical.iter().map(|&angle| {
+            let t = ((outer - angle) / (outer - inner).max(EPS)).clamp(0., 1.);
+            t * t * (3. - 2. * t)
+        }).collect();
+
+        IesProfile::new(vertical, vec![0.], vec![row])
+    }
+
+    /// Intensity in the direction given by the angles (in degrees), using the file's symmetry
+    pub fn lookup(&self, vertical: Float, horizontal: Float) -> Float {
+        let last = *self.horizontal.last().unwrap();
+        let mut h = horizontal.rem_euclid(360.);
+
+        if self.horizontal.len() == 1 {
+            // Rotationally symmetric
+            h = self.horizontal[0];
+        } else if last <= 90. {
+            // Symmetric in each quadrant
+            h %= 180.;
+            if h > 90. { h = 180. - h; }
+        } else if last <= 180. {
+            // Symmetric about the 0-180 plane
+            if h > 180. { h = 360. - h; }
+        }
+
+        let (v0, v1, tv) = match interval(&self.vertical, vertical) {
+            Some(found) => found,
+            None => return 0., // Outside the measured angles
+        };
+        let (h0, h1, th) = match interval(&self.horizontal, h) {
+            Some(found) => found,
+            None => {
+                // Between the last angle and 360, wrapping back round to the first
+                let first = self.horizontal[0];
+                let n = self.horizontal.len() - 1;
+                let wrapped = if h < first { h + 360. } else { h };
+                (n, 0, (wrapped - last) / (first + 360. - last).max(EPS))
+            }
+        };
+
+        let row = |i: usize| (1. - tv) * self.candela[i][v0] + tv * self.candela[i][v1];
+        (1. - th) * row(h0) + th * row(h1)
+    }
+
+    pub fn max_candela(&self) -> Float {
+        self.candela.iter().flat_map(|row| row.iter()).fold(0., |a: Float, &b| a.max(b))
+    }
+
+    /// Total flux by integrating over the sphere; 1 degree by 5 degree steps
+    fn integrate(&self) -> Float {
+        let mut flux = 0.;
+        let (d_theta, d_phi) = (PI / 180., 5. * PI / 180.);
+
+        for i in 0..180 {
+            let theta = (i as Float + 0.5) * d_theta;
+            for j in 0..72 {
+                let phi = (j as Float + 0.5) * d_phi;
+                flux += self.lookup(theta.to_degrees(), phi.to_degrees()) * theta.sin() * d_theta * d_phi;
+            }
+        }
+
+        flux
+    }
+}
+
+/// Where x falls in the increasing angles: the indices either side and how far along it is
+fn interval(angles: &[Float], x: Float) -> Option<(usize, usize, Float)> {
+    if angles.len() == 1 {
+        return if (x - angles[0]).abs() < EPS { Some((0, 0, 0.)) } else { None };
+    }
+    if x < angles[0] || x > angles[angles.len() - 1] {
+        return None;
+    }
+
+    let i = angles.windows(2).position(|pair| x <= pair[1]).unwrap_or(angles.len() - 2);
+    let t = (x - angles[i]) / (angles[i + 1] - angles[i]).max(EPS);
+    Some((i, i + 1, t))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Float, b: Float) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn parse_and_lookup() {
+        let text = "IESNA:LM-63-2002\n[TEST] Two angles\nTILT=NONE\n1 -1 1 2 1 1 2 0 0 0\n1 1 0\n0 90\n0\n100 50\n";
+        let profile = IesProfile::parse(text).unwrap();
+
+        assert_eq!(profile.vertical, vec![0., 90.]);
+        assert_eq!(profile.horizontal, vec![0.]);
+        // One horizontal angle is the same all the way round
+        assert!(close(profile.lookup(0., 0.), 100.));
+        assert!(close(profile.lookup(90., 0.), 50.));
+        assert!(close(profile.lookup(90., 123.), 50.));
+        assert!(close(profile.lookup(45., 0.), 75.));
+        assert_eq!(profile.lookup(120., 0.), 0.);
+    }
+
+    #[test]
+    fn quadrant_symmetry_wraps() {
+        let text = "TILT=NONE\n1 -1 1 2 2 1 2 0 0 0\n1 1 0\n0 90\n30 90\n100 50\n200 100\n";
+        let profile = IesProfile::parse(text).unwrap();
+
+        assert!(close(profile.lookup(0., 30.), 100.));
+        assert!(close(profile.lookup(0., 90.), 200.));
+        // Mirrored into the first quadrant
+        assert!(close(profile.lookup(0., 150.), 100.));
+        assert!(close(profile.lookup(0., 270.), 200.));
+        // Below the first angle it wraps round from the last, without extrapolating
+        let t = (10. + 360. - 90.) / (30. + 360. - 90.);
+        assert!(close(profile.lookup(0., 10.), (1. - t) * 200. + t * 100.));
+    }
+}
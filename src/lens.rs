@@ -0,0 +1,343 @@
+/* A camera that traces rays through a real lens: a stack of spherical surfaces from a patent-style
+   prescription, after pbrt's RealisticCamera. Gives vignetting, distortion and focus breathing that
+   the thin lens in DOFCamera can't.
+   Lens space has the film at z = 0 and the lens towards -z, the front element furthest away */
+#![allow(dead_code)]
+use std::fs;
+use std::path::Path;
+use rayon::prelude::*;
+use super::vector::*;
+use super::sampler::{Sampler, radical_inverse};
+use super::camera::Camera;
+use super::camera_model::CameraModel;
+
+/// One surface of the lens. Distances are in scene units (metres from a prescription file)
+#[derive(Copy, Clone)]
+pub struct LensElement {
+    pub radius: Float, // Of curvature; positive bulges towards the scene, 0 for the aperture stop
+    pub thickness: Float, // Along the axis to the next surface, or to the film for the last
+    pub ior: Float, // Of the glass behind this surface (towards the film); 1 for air
+    pub aperture_radius: Float,
+}
+
+/// Rectangle on the rear element's plane, as (min x, min y, max x, max y)
+type Bounds = (Float, Float, Float, Float);
+
+/// Number of film radii the exit pupil is worked out for
+const PUPIL_BOUNDS: usize = 64;
+/// Rays traced per film radius when bounding the exit pupil, unless the Lens says otherwise
+const PUPIL_SAMPLES: usize = 64 * 64;
+
+/// The lens and film a LensCamera is built from; the CameraModel says where it is and what it focuses on
+#[derive(Clone)]
+pub struct Lens {
+    pub elements: Vec<LensElement>, // Front to back
+    pub film_diagonal: Float,
+    pub stop_diameter: Option<Float>, // Smaller than the prescription's stops the lens down
+    pub pupil_samples: usize, // More gives tighter exit pupils (fewer wasted rays) but takes longer to set up
+}
+
+impl Lens {
+    pub fn new(elements: Vec<LensElement>, film_diagonal: Float) -> Lens {
+        Lens {
+            elements,
+            film_diagonal,
+            stop_diameter: None,
+            pupil_samples: PUPIL_SAMPLES,
+        }
+    }
+}
+
+pub struct LensCamera {
+    pub position: Vec3,
+    pub looking: Vec3,
+    pub side: Vec3,
+    pub up: Vec3,
+    pub elements: Vec<LensElement>, // Front to back
+    pub film_width: Float,
+    pub film_height: Float,
+    exit_pupils: Vec<Bounds>, // For film radii from the middle to the corner
+    largest_pupil: Float, // Area of the biggest of them
+}
+
+/// Snell's law; None for total internal reflection. eta is the ratio of IORs, incident over transmitted
+fn refract(incident: Vec3, normal: Vec3, eta: Float) -> Option<Vec3> {
+    let cos_i = -dot(incident, normal);
+    let sin2_t = eta * eta * (1. - cos_i * cos_i).max(0.);
+    if sin2_t >= 1. { return None };
+
+    let cos_t = (1. - sin2_t).sqrt();
+    Some(eta * incident + (eta * cos_i - cos_t) * normal)
+}
+
+/// Where a ray meets a spherical surface, with the normal facing back along the ray
+fn intersect_element(ray: Ray, radius: Float, z_centre: Float) -> Option<(Float, Vec3)> {
+    let o = ray.origin - Vec3::new(0., 0., z_centre);
+    let a = ray.direction.norm_squared();
+    let b = 2. * dot(ray.direction, o);
+    let c = o.norm_squared() - radius * radius;
+
+    let discriminant = b * b - 4. * a * c;
+    if discriminant < 0. { return None };
+    let root = discriminant.sqrt();
+    let (t0, t1) = ((-b - root) / (2. * a), (-b + root) / (2. * a));
+
+    // Which root is the surface depends on which way the ray goes and which way the surface bulges
+    let closer = (ray.direction.z > 0.) ^ (radius < 0.);
+    let t = if closer { t0.min(t1) } else { t0.max(t1) };
+    if t < 0. { return None };
+
+    let normal = (o + t * ray.direction).normalise();
+    Some((t, if dot(normal, ray.direction) > 0. { -normal } else { normal }))
+}
+
+impl LensCamera {
+    /// Read a prescription in the usual table layout, one surface a line front to back:
+    /// radius, thickness, IOR (0 for air) and aperture diameter, all in mm. # starts a comment
+    pub fn load_prescription(path: &Path) -> Result<Vec<LensElement>, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut elements = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() { continue };
+
+            let values = line.split_whitespace().map(|value| value.parse::<Float>())
+                .collect::<Result<Vec<Float>, _>>().map_err(|e| format!("Line {}: {}", i + 1, e))?;
+            if values.len() != 4 {
+                return Err(format!("Line {}: expected 4 numbers, found {}", i + 1, values.len()));
+            }
+
+            elements.push(LensElement {
+                radius: 0.001 * values[0],
+                thickness: 0.001 * values[1],
+                ior: if values[2] == 0. { 1. } else { values[2] },
+                aperture_radius: 0.001 * values[3] / 2.,
+            });
+        }
+
+        if elements.is_empty() {
+            return Err("No lens elements".to_string());
+        }
+        Ok(elements)
+    }
+
+    /// Placed as the model says, with width / height as the film's aspect, and focused at the
+    /// model's focus distance (from the film) by moving the lens. The model's fov and aperture
+    /// are ignored; the lens and film decide those
+    pub fn from_model(model: &CameraModel, lens: &Lens, width: usize, height: usize) -> Result<LensCamera, String> {
+        let (side, up) = model.basis();
+        let aspect = width as Float / height as Float;
+        let film_height = lens.film_diagonal / (1. + aspect * aspect).sqrt();
+
+        let mut camera = LensCamera {
+            position: model.position,
+            looking: model.looking,
+            side,
+            up,
+            elements: lens.elements.clone(),
+            film_width: aspect * film_height,
+            film_height,
+            exit_pupils: Vec::new(),
+            largest_pupil: 0.,
+        };
+
+        if let Some(diameter) = lens.stop_diameter {
+            for element in camera.elements.iter_mut().filter(|element| element.radius == 0.) {
+                element.aperture_radius = element.aperture_radius.min(diameter / 2.);
+            }
+        }
+
+        let back_focus = camera.focus_thick_lens(model.focus_distance)?;
+        camera.elements.last_mut().unwrap().thickness = back_focus;
+
+        camera.exit_pupils = (0..PUPIL_BOUNDS).into_par_iter().map(|i| {
+            let radius = 0.5 * camera.film_diagonal();
+            camera.bound_exit_pupil(i as Float / PUPIL_BOUNDS as Float * radius, (i + 1) as Float / PUPIL_BOUNDS as Float * radius, lens.pupil_samples)
+        }).collect();
+        camera.largest_pupil = camera.exit_pupils.iter().map(|&bounds| LensCamera::bounds_area(bounds)).fold(0., Float::max);
+
+        Ok(camera)
+    }
+
+    fn film_diagonal(&self) -> Float {
+        (self.film_width * self.film_width + self.film_height * self.film_height).sqrt()
+    }
+
+    fn front_z(&self) -> Float {
+        self.elements.iter().map(|element| element.thickness).sum()
+    }
+
+    fn rear_z(&self) -> Float {
+        self.elements.last().unwrap().thickness
+    }
+
+    fn rear_radius(&self) -> Float {
+        self.elements.last().unwrap().aperture_radius
+    }
+
+    /// Take a ray from the film out of the front of the lens, unless something blocks it
+    fn trace_from_film(&self, ray: Ray) -> Option<Ray> {
+        let mut ray = ray;
+        let mut z = 0.;
+
+        for i in (0..self.elements.len()).rev() {
+            let element = self.elements[i];
+            z -= element.thickness;
+
+            let (t, normal) = if element.radius == 0. {
+                if ray.direction.z >= 0. { return None };
+                ((z - ray.origin.z) / ray.direction.z, Vec3::ZERO)
+            } else {
+                intersect_element(ray, element.radius, z + element.radius)?
+            };
+
+            let hit = ray.eval(t);
+            if hit.x * hit.x + hit.y * hit.y > element.aperture_radius * element.aperture_radius { return None };
+            ray.origin = hit;
+
+            if element.radius != 0. {
+                let next_ior = if i > 0 { self.elements[i - 1].ior } else { 1. };
+                ray.direction = refract(ray.direction.normalise(), normal, element.ior / next_ior)?;
+            }
+        }
+        Some(ray)
+    }
+
+    /// Take a ray from the scene through the lens to the film side
+    fn trace_from_scene(&self, ray: Ray) -> Option<Ray> {
+        let mut ray = ray;
+        let mut z = -self.front_z();
+
+        for i in 0..self.elements.len() {
+            let element = self.elements[i];
+
+            let (t, normal) = if element.radius == 0. {
+                if ray.direction.z <= 0. { return None };
+                ((z - ray.origin.z) / ray.direction.z, Vec3::ZERO)
+            } else {
+                intersect_element(ray, element.radius, z + element.radius)?
+            };
+
+            let hit = ray.eval(t);
+            if hit.x * hit.x + hit.y * hit.y > element.aperture_radius * element.aperture_radius { return None };
+            ray.origin = hit;
+
+            if element.radius != 0. {
+                let previous_ior = if i > 0 { self.elements[i - 1].ior } else { 1. };
+                ray.direction = refract(ray.direction.normalise(), normal, previous_ior / element.ior)?;
+            }
+            z += element.thickness;
+        }
+        Some(ray)
+    }
+
+    /// Principal plane and focal point along z for a ray parallel to the axis, from before and after the lens
+    fn cardinal_points(ray_in: Ray, ray_out: Ray) -> (Float, Float) {
+        let focal = ray_out.eval(-ray_out.origin.x / ray_out.direction.x).z;
+        let principal = ray_out.eval((ray_in.origin.x - ray_out.origin.x) / ray_out.direction.x).z;
+        (principal, focal)
+    }
+
+    /// The distance from the rear element to the film that focuses at focus_distance, by treating
+    /// the lens as a thick lens found from paraxial rays traced each way
+    fn focus_thick_lens(&self, focus_distance: Float) -> Result<Float, String> {
+        let x = 0.001 * self.film_diagonal();
+        let paraxial = "A ray near the axis didn't get through the lens";
+
+        let scene_ray = Ray::new(Vec3::new(x, 0., -self.front_z() - 1.), Vec3::Z);
+        let out = self.trace_from_scene(scene_ray).ok_or(paraxial)?;
+        let (principal_scene, focal_scene) = LensCamera::cardinal_points(scene_ray, out);
+
+        let film_ray = Ray::new(Vec3::new(x, 0., 1.), -Vec3::Z);
+        let out = self.trace_from_film(film_ray).ok_or(paraxial)?;
+        let (principal_film, _) = LensCamera::cardinal_points(film_ray, out);
+
+        // Solve the thick lens equation for how far the lens has to move
+        let focal_length = focal_scene - principal_scene;
+        let z = -focus_distance;
+        let c = (principal_film - z - principal_scene) * (principal_film - z - 4. * focal_length - principal_scene);
+        if c <= 0. {
+            return Err("The lens can't focus that close".to_string());
+        }
+        Ok(self.rear_z() + 0.5 * (principal_film - z + principal_scene - c.sqrt()))
+    }
+
+    /// Bounds of where rays from a ring of the film get through the whole lens, on the rear element's plane
+    fn bound_exit_pupil(&self, film_r0: Float, film_r1: Float, samples: usize) -> Bounds {
+        let rear_radius = 1.5 * self.rear_radius();
+        let mut bounds: Option<Bounds> = None;
+
+        for i in 0..samples {
+            let film = Vec3::new(film_r0 + (i as Float + 0.5) / samples as Float * (film_r1 - film_r0), 0., 0.);
+            // A Halton point over the square around the rear element
+            let (u, v) = (radical_inverse(2, i as u32), radical_inverse(3, i as u32));
+            let rear = Vec3::new((2. * u - 1.) * rear_radius, (2. * v - 1.) * rear_radius, -self.rear_z());
+
+            let inside = bounds.is_some_and(|(x0, y0, x1, y1)| rear.x >= x0 && rear.x <= x1 && rear.y >= y0 && rear.y <= y1);
+            if inside || self.trace_from_film(Ray::new(film, rear - film)).is_some() {
+                bounds = Some(match bounds {
+                    Some((x0, y0, x1, y1)) => (x0.min(rear.x), y0.min(rear.y), x1.max(rear.x), y1.max(rear.y)),
+                    None => (rear.x, rear.y, rear.x, rear.y),
+                });
+            }
+        }
+
+        // Grow by about the spacing of the samples so nothing that gets through is missed
+        let margin = 2. * (2. * 2f32.sqrt() * rear_radius) / (samples as Float).sqrt();
+        match bounds {
+            Some((x0, y0, x1, y1)) => (x0 - margin, y0 - margin, x1 + margin, y1 + margin),
+            None => (-rear_radius, -rear_radius, rear_radius, rear_radius),
+        }
+    }
+
+    fn bounds_area((x0, y0, x1, y1): Bounds) -> Float {
+        (x1 - x0) * (y1 - y0)
+    }
+}
+
+impl Camera for LensCamera {
+    /// Rays that don't get through come back with no direction; the integrator checks for these
+    /// and counts them black without tracing them.
+    /// Rays carry no weight, so the cos^4 falloff and the size of the exit pupil are played as
+    /// russian roulette against the brightest part of the film
+    fn generate_ray_at(&self, film_x: Float, film_y: Float, width: usize, height: usize, sampler: &mut dyn Sampler) -> Ray {
+        let blocked = Ray::new(self.position, Vec3::ZERO);
+
+        // The lens turns the image upside down, so the top of the image is the bottom of the film
+        // and its left is the film's right
+        let film = Vec3::new((0.5 - film_x / width as Float) * self.film_width,
+                             (film_y / height as Float - 0.5) * self.film_height, 0.);
+
+        // The exit pupil for this film radius, rotated round to the point's angle
+        let film_r = (film.x * film.x + film.y * film.y).sqrt();
+        let index = ((film_r / (0.5 * self.film_diagonal()) * PUPIL_BOUNDS as Float) as usize).min(PUPIL_BOUNDS - 1);
+        let bounds = self.exit_pupils[index];
+        let (x0, y0, x1, y1) = bounds;
+        let (u, v) = (random_float(sampler), random_float(sampler));
+        let (pupil_x, pupil_y) = (x0 + u * (x1 - x0), y0 + v * (y1 - y0));
+        let (sin, cos) = if film_r > 0. { (film.y / film_r, film.x / film_r) } else { (0., 1.) };
+        let rear = Vec3::new(cos * pupil_x - sin * pupil_y, sin * pupil_x + cos * pupil_y, -self.rear_z());
+
+        let film_ray = Ray::new(film, rear - film);
+        let out = match self.trace_from_film(film_ray) {
+            Some(out) => out,
+            None => return blocked,
+        };
+
+        let cos_theta = -film_ray.direction.normalise().z;
+        let weight = cos_theta.powi(4) * LensCamera::bounds_area(bounds) / self.largest_pupil;
+        if random_float(sampler) >= weight { return blocked };
+
+        // Lens space has x right, y up and the scene down -z; side points left
+        let to_world = |v: Vec3| -v.x * self.side + v.y * self.up - v.z * self.looking;
+        Ray::new(self.position + to_world(out.origin), to_world(out.direction).normalise())
+    }
+
+
+    fn translate(&mut self, direction: Vec3) {
+        self.position += direction.x * self.looking
+            + direction.y * self.side
+            + direction.z * self.up;
+    }
+}
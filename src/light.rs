@@ -0,0 +1,526 @@
+/* Light selection for direct lighting; emitters are picked in proportion to their power,
+   or by walking a light BVH which also looks at distance and orientation */
+#![allow(dead_code, unused_imports)]
+use std::sync::Arc;
+use super::vector::*;
+use super::ies::IesProfile;
+use super::shape::*;
+use super::material::Material;
+use super::colour::*;
+use super::object::*;
+use super::sampler::Sampler;
+
+/// The part of an emitter's shape needed to sample it; only finite things can be lights
+#[derive(Clone)]
+pub enum EmitterShape {
+    Triangle { a: Vec3, b: Vec3, c: Vec3, normal: Vec3 },
+    Sphere { centre: Vec3, radius: Float },
+    Surface(Arc<dyn Surface + Sync + Send>), // Disks, cylinders, cones, quads and boxes
+    // A point whose intensity follows a photometric profile; the profile's nadir points
+    // along down and its zero horizontal angle along forward
+    Point { position: Vec3, down: Vec3, forward: Vec3, profile: Arc<IesProfile> },
+}
+
+/// Something which gives off light, with constant radiance over its surface
+/// (for points, the radiance scales the profile's intensity)
+#[derive(Clone)]
+pub struct Emitter {
+    pub shape: EmitterShape,
+    pub radiance: Colour,
+    pub two_sided: bool, // Material::Light emits from both sides, LightUni only along the normal
+}
+
+/// A sampled point on a light, as seen from the shading point.
+/// Points can't be hit, so their pdf is only the chance of picking them
+/// and the radiance is really intensity over distance squared
+#[derive(Copy, Clone)]
+pub struct LightSample {
+    pub direction: Vec3, // Normalised, from the shading point to the light
+    pub dist: Float,
+    pub radiance: Colour,
+    pub pdf: Float, // Solid angle pdf, including the probability of picking this light
+}
+
+/// The radiance and sidedness of a material, if it is one we can sample directly.
+/// LightCos is so directional that it is left to be found by bouncing rays
+pub fn emission(material: Material, colour: Colour) -> Option<(Colour, bool)> {
+    match material {
+        Material::Light(intensity) => Some((intensity * colour, true)),
+        Material::LightUni(intensity) => Some((intensity * colour, false)),
+        _ => None,
+    }
+}
+
+impl Emitter {
+    pub fn area(&self) -> Float {
+        match self.shape {
+            EmitterShape::Triangle { a, b, c, .. } => cross(b - a, c - a).norm() / 2.,
+            EmitterShape::Sphere { radius, .. } => 4. * PI * radius * radius,
+            EmitterShape::Surface(ref surface) => surface.area(),
+            EmitterShape::Point { .. } => 0.,
+        }
+    }
+
+    /// Total emitted power (well, its luminance)
+    pub fn power(&self) -> Float {
+        if let EmitterShape::Point { profile, .. } = &self.shape {
+            return profile.flux * self.radiance.luminance();
+        }
+
+        let sides = if self.two_sided { 2. } else { 1. };
+        sides * PI * self.area() * self.radiance.luminance()
+    }
+
+    fn centre(&self) -> Vec3 {
+        match self.shape {
+            EmitterShape::Triangle { a, b, c, .. } => (a + b + c) / 3.,
+            EmitterShape::Sphere { centre, .. } => centre,
+            EmitterShape::Surface(ref surface) => {
+                let (lo, hi) = surface.bounds();
+                0.5 * (lo + hi)
+            },
+            EmitterShape::Point { position, .. } => position,
+        }
+    }
+
+    fn bounds(&self) -> (Vec3, Vec3) {
+        match self.shape {
+            EmitterShape::Triangle { a, b, c, .. } => (min(a, min(b, c)), max(a, max(b, c))),
+            EmitterShape::Sphere { centre, radius } => {
+                let r = Vec3::new(radius, radius, radius);
+                (centre - r, centre + r)
+            }
+            EmitterShape::Surface(ref surface) => surface.bounds(),
+            EmitterShape::Point { position, .. } => (position, position),
+        }
+    }
+
+    /// Whether a hit point (with the normal there) lies on this light
+    fn contains(&self, point: Vec3, normal: Vec3) -> bool {
+        const TOLERANCE: Float = 1e-3;
+        let (lo, hi) = self.bounds();
+        let slack = Vec3::new(TOLERANCE, TOLERANCE, TOLERANCE);
+        let (lo, hi) = (lo - slack, hi + slack);
+        if point.x < lo.x || point.y < lo.y || point.z < lo.z || point.x > hi.x || point.y > hi.y || point.z > hi.z {
+            return false;
+        }
+
+        match self.shape {
+            EmitterShape::Triangle { a, b, c, normal: n } => {
+                // In the plane, and on the inside of all three edges
+                let area = dot(cross(b - a, c - a), n);
+                dot(point - a, n).abs() < TOLERANCE
+                    && dot(cross(b - a, point - a), n) / area > -TOLERANCE
+                    && dot(cross(c - b, point - b), n) / area > -TOLERANCE
+                    && dot(cross(a - c, point - c), n) / area > -TOLERANCE
+            },
+            EmitterShape::Sphere { centre, radius } => ((point - centre).norm() - radius).abs() < TOLERANCE,
+            // Back onto the surface from just off it
+            EmitterShape::Surface(ref surface) => surface.intersect(Ray::new(point + TOLERANCE * normal, -normal))
+                .is_some_and(|hit| hit.dist < 2. * TOLERANCE),
+            EmitterShape::Point { .. } => false,
+        }
+    }
+
+    /// The cone (axis, half angle) containing every direction light leaves in
+    fn cone(&self) -> (Vec3, Float) {
+        match self.shape {
+            EmitterShape::Triangle { normal, .. } if !self.two_sided => (normal, PI / 2.),
+            EmitterShape::Triangle { normal, .. } => (normal, PI),
+            EmitterShape::Surface(ref surface) => match surface.flat() {
+                Some(normal) if !self.two_sided => (normal, PI / 2.),
+                _ => (Vec3::Y, PI),
+            },
+            EmitterShape::Sphere { .. } | EmitterShape::Point { .. } => (Vec3::Y, PI),
+        }
+    }
+
+    /// Goniometric lookup for profiled points; the intensity leaving in a direction
+    fn intensity(&self, direction: Vec3) -> Colour {
+        match &self.shape {
+            EmitterShape::Point { down, forward, profile, .. } => {
+                let side = cross(*down, *forward);
+                let vertical = dot(direction, *down).clamp(-1., 1.).acos().to_degrees();
+                let horizontal = dot(direction, side).atan2(dot(direction, *forward)).to_degrees();
+                profile.lookup(vertical, horizontal) * self.radiance
+            },
+            _ => self.radiance,
+        }
+    }
+
+    /// Sample a point uniformly by area, returning it and the normal there
+    pub fn sample_point(&self, u1: Float, u2: Float) -> (Vec3, Vec3) {
+        match self.shape {
+            EmitterShape::Triangle { a, b, c, normal } => {
+                // Square root parametrisation; uniform over the triangle
+                let s = u1.sqrt();
+                (a + s * (1. - u2) * (b - a) + s * u2 * (c - a), normal)
+            }
+            EmitterShape::Sphere { centre, radius } => {
+                let z = 1. - 2. * u1;
+                let r = (1. - z * z).max(0.).sqrt();
+                let phi = 2. * PI * u2;
+                let normal = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+                (centre + radius * normal, normal)
+            }
+            EmitterShape::Surface(ref surface) => surface.sample(u1, u2),
+            EmitterShape::Point { position, down, .. } => (position, down),
+        }
+    }
+
+    /// Sample this light from a point, returning the solid angle pdf of the light only
+    pub fn sample(&self, point: Vec3, sampler: &mut dyn Sampler) -> Option<LightSample> {
+        if let EmitterShape::Point { position, .. } = self.shape {
+            let to_light = position - point;
+            let dist = to_light.norm();
+            if dist < EPS { return None; }
+
+            let direction = to_light / dist;
+            return Some(LightSample {
+                direction,
+                dist,
+                radiance: (1. / (dist * dist)) * self.intensity(-direction),
+                pdf: 1.,
+            });
+        }
+
+        let (light_point, normal) = self.sample_point(random_float(sampler), random_float(sampler));
+        let to_light = light_point - point;
+        let dist = to_light.norm();
+        if dist < EPS { return None; }
+
+        let direction = to_light / dist;
+        let cos = -dot(direction, normal);
+
+        if cos <= EPS && !(self.two_sided && cos < -EPS) {
+            // Back of a one sided light (or grazing)
+            return None;
+        }
+
+        Some(LightSample {
+            direction,
+            dist,
+            radiance: self.radiance,
+            pdf: dist * dist / (cos.abs() * self.area()),
+        })
+    }
+}
+
+fn min(u: Vec3, v: Vec3) -> Vec3 {
+    Vec3::new(u.x.min(v.x), u.y.min(v.y), u.z.min(v.z))
+}
+
+fn max(u: Vec3, v: Vec3) -> Vec3 {
+    Vec3::new(u.x.max(v.x), u.y.max(v.y), u.z.max(v.z))
+}
+
+/// All the triangles of an emissive collection as lights
+pub fn emitters_from_collection(collection: &ObjectCollection<Triangle>) -> Vec<Emitter> {
+    match emission(collection.material, collection.colour) {
+        Some((radiance, two_sided)) => collection.shapes.iter().map(|triangle| Emitter {
+            shape: EmitterShape::Triangle { a: triangle.a, b: triangle.b, c: triangle.c, normal: triangle.normal },
+            radiance,
+            two_sided,
+        }).collect(),
+        None => vec![],
+    }
+}
+
+/// An emissive sphere as a light (spheres only emit outwards anyway)
+pub fn emitters_from_sphere(object: &GeneralObject<Sphere>) -> Vec<Emitter> {
+    match emission(object.material, object.colour) {
+        Some((radiance, _)) => vec![Emitter {
+            shape: EmitterShape::Sphere { centre: object.shape.centre, radius: object.shape.radius },
+            radiance,
+            two_sided: false,
+        }],
+        None => vec![],
+    }
+}
+
+/// An emissive analytic shape as a light; only flat ones can emit from both sides,
+/// closed ones emit outwards like spheres
+pub fn emitters_from_surface<S>(object: &GeneralObject<S>) -> Vec<Emitter> where S: Surface + Copy + Sync + Send + 'static {
+    match emission(object.material, object.colour) {
+        Some((radiance, two_sided)) => vec![Emitter {
+            shape: EmitterShape::Surface(Arc::new(object.shape)),
+            radiance,
+            two_sided: two_sided && object.shape.flat().is_some(),
+        }],
+        None => vec![],
+    }
+}
+
+/// A point light following a photometric profile; down is where the profile's nadir
+/// points and forward where its zero horizontal angle is. The colour scales the candela values
+pub fn profiled_light(position: Vec3, down: Vec3, forward: Vec3, colour: Colour, profile: Arc<IesProfile>) -> Emitter {
+    let down = down.normalise();
+    let forward = (forward - dot(forward, down) * down).normalise();
+
+    Emitter {
+        shape: EmitterShape::Point { position, down, forward, profile },
+        radiance: colour,
+        two_sided: false,
+    }
+}
+
+/// A spot light pointing along direction; full intensity within the inner angle,
+/// fading out by the outer one (in degrees)
+pub fn spot_light(position: Vec3, direction: Vec3, colour: Colour, inner: Float, outer: Float) -> Emitter {
+    let (forward, _) = onb(direction.normalise());
+    profiled_light(position, direction, forward, colour, Arc::new(IesProfile::spot(inner, outer)))
+}
+
+
+/*************************** LIGHT BVH ***************************/
+
+
+enum LightNodeContent {
+    Leaf(usize), // Index of the emitter
+    Interior(usize, usize), // Indices of the children
+}
+
+/// A node holds enough to guess how much light its emitters send to a point
+struct LightNode {
+    min: Vec3,
+    max: Vec3,
+    power: Float,
+    axis: Vec3,
+    theta_o: Float, // Half angle of the cone of normals
+    content: LightNodeContent,
+}
+
+/// Smallest cone containing both cones; as in Conty and Kulla's light tree
+fn cone_union(a: (Vec3, Float), b: (Vec3, Float)) -> (Vec3, Float) {
+    let (a, b) = if b.1 > a.1 { (b, a) } else { (a, b) };
+    let theta_d = dot(a.0, b.0).clamp(-1., 1.).acos();
+
+    if (theta_d + b.1).min(PI) <= a.1 { return a; }
+
+    let theta_o = (a.1 + theta_d + b.1) / 2.;
+    if theta_o >= PI { return (a.0, PI); }
+
+    // Rotate a's axis towards b's
+    let perpendicular = b.0 - dot(a.0, b.0) * a.0;
+    if perpendicular.norm() < EPS { return (a.0, PI); }
+
+    let theta_r = theta_o - a.1;
+    (theta_r.cos() * a.0 + theta_r.sin() * perpendicular.normalise(), theta_o)
+}
+
+impl LightNode {
+    /// A cheap upper-ish bound of the light reaching a point with a given normal
+    fn importance(&self, point: Vec3, normal: Vec3) -> Float {
+        let centre = 0.5 * (self.min + self.max);
+        let half_diagonal = 0.5 * (self.max - self.min).norm();
+        let to_point = point - centre;
+
+        // Don't let the distance get smaller than the node, points inside it blow up otherwise
+        let dist2 = to_point.norm_squared().max(half_diagonal * half_diagonal).max(EPS);
+        let dist = dist2.sqrt();
+
+        // Angle the bounds subtend from the point
+        let theta_u = if dist > half_diagonal { (half_diagonal / dist).asin() } else { PI };
+        let direction = to_point / dist;
+
+        // Emitter orientation; no light leaves outside theta_o (+ the spread of the bounds)
+        let theta = dot(self.axis, direction).clamp(-1., 1.).acos();
+        let theta_prime = (theta - self.theta_o - theta_u).max(0.);
+        if theta_prime >= PI / 2. { return 0.; }
+
+        // Receiver orientation, with the same widening
+        let cos_receiver = dot(normal, -direction).clamp(-1., 1.);
+        let receiver = (cos_receiver.acos() - theta_u).max(0.);
+        if receiver >= PI / 2. { return 0.; }
+
+        self.power * theta_prime.cos() * receiver.cos() / dist2
+    }
+}
+
+/// Bounding volume hierarchy over the emitters
+pub struct LightTree {
+    nodes: Vec<LightNode>,
+}
+
+impl LightTree {
+    pub fn new(emitters: &[Emitter]) -> LightTree {
+        let mut tree = LightTree { nodes: vec![] };
+        let mut indices: Vec<usize> = (0..emitters.len()).collect();
+
+        if !indices.is_empty() {
+            tree.build(emitters, &mut indices);
+        }
+
+        tree
+    }
+
+    /// Builds the subtree over the given emitters, returning its node index;
+    /// splits at the median centroid along the widest axis
+    fn build(&mut self, emitters: &[Emitter], indices: &mut [usize]) -> usize {
+        if indices.len() == 1 {
+            let emitter = &emitters[indices[0]];
+            let (min, max) = emitter.bounds();
+            let (axis, theta_o) = emitter.cone();
+
+            self.nodes.push(LightNode {
+                min, max, axis, theta_o,
+                power: emitter.power(),
+                content: LightNodeContent::Leaf(indices[0]),
+            });
+            return self.nodes.len() - 1;
+        }
+
+        let (mut low, mut high) = (emitters[indices[0]].centre(), emitters[indices[0]].centre());
+        for &i in indices.iter() {
+            low = min(low, emitters[i].centre());
+            high = max(high, emitters[i].centre());
+        }
+        let extent = high - low;
+        let key = |e: &Emitter| {
+            let c = e.centre();
+            if extent.x >= extent.y && extent.x >= extent.z { c.x } else if extent.y >= extent.z { c.y } else { c.z }
+        };
+        indices.sort_by(|&i, &j| key(&emitters[i]).partial_cmp(&key(&emitters[j])).unwrap());
+
+        let middle = indices.len() / 2;
+        let (left_indices, right_indices) = indices.split_at_mut(middle);
+        let left = self.build(emitters, left_indices);
+        let right = self.build(emitters, right_indices);
+
+        let (l, r) = (&self.nodes[left], &self.nodes[right]);
+        let (axis, theta_o) = cone_union((l.axis, l.theta_o), (r.axis, r.theta_o));
+        let node = LightNode {
+            min: min(l.min, r.min),
+            max: max(l.max, r.max),
+            power: l.power + r.power,
+            axis, theta_o,
+            content: LightNodeContent::Interior(left, right),
+        };
+
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    /// Walk down the tree choosing children by importance; returns the emitter and its probability
+    fn pick(&self, point: Vec3, normal: Vec3, mut u: Float) -> Option<(usize, Float)> {
+        // The root is pushed last
+        let mut node = self.nodes.last()?;
+        let mut pmf = 1.;
+
+        loop {
+            match node.content {
+                LightNodeContent::Leaf(index) => return Some((index, pmf)),
+                LightNodeContent::Interior(left, right) => {
+                    let left_importance = self.nodes[left].importance(point, normal);
+                    let right_importance = self.nodes[right].importance(point, normal);
+                    let total = left_importance + right_importance;
+                    if total <= 0. { return None; }
+
+                    // Reuse the random number after rescaling it
+                    let p_left = left_importance / total;
+                    if u < p_left {
+                        u /= p_left;
+                        pmf *= p_left;
+                        node = &self.nodes[left];
+                    } else {
+                        u = ((u - p_left) / (1. - p_left)).min(1. - EPS);
+                        pmf *= 1. - p_left;
+                        node = &self.nodes[right];
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+/*************************** SAMPLER ***************************/
+
+
+/// How the light sampler chooses which emitter to sample
+pub enum LightSelection {
+    Power(Vec<Float>), // The normalised CDF over emitter powers
+    Tree(LightTree),
+}
+
+/// Holds the emitters direct lighting samples; anything emissive that isn't in here
+/// is only found by rays bouncing into it
+pub struct LightSampler {
+    pub emitters: Vec<Emitter>,
+    pub selection: LightSelection,
+}
+
+impl LightSampler {
+    /// Picks emitters in proportion to their power, or all alike if they have none between them
+    pub fn new(emitters: Vec<Emitter>) -> LightSampler {
+        let mut cdf = Vec::with_capacity(emitters.len());
+        let mut total = 0.;
+        for emitter in emitters.iter() {
+            total += emitter.power();
+            cdf.push(total);
+        }
+
+        if total > 0. && total.is_finite() {
+            for c in cdf.iter_mut() {
+                *c /= total;
+            }
+        } else {
+            let n = cdf.len() as Float;
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = (i + 1) as Float / n;
+            }
+        }
+
+        LightSampler {
+            emitters,
+            selection: LightSelection::Power(cdf),
+        }
+    }
+
+    /// Picks emitters using a light BVH, so near and facing lights get more samples
+    pub fn with_tree(emitters: Vec<Emitter>) -> LightSampler {
+        let tree = LightTree::new(&emitters);
+        LightSampler {
+            emitters,
+            selection: LightSelection::Tree(tree),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.emitters.is_empty()
+    }
+
+    /// Whether a hit is on one of these emitters, so direct lighting has already counted it
+    pub fn contains(&self, point: Vec3, normal: Vec3) -> bool {
+        self.emitters.iter().any(|emitter| emitter.contains(point, normal))
+    }
+
+    /// Choose an emitter for the shading point; returns its index and probability
+    pub fn pick(&self, point: Vec3, normal: Vec3, u: Float) -> Option<(usize, Float)> {
+        if self.is_empty() { return None; }
+
+        match &self.selection {
+            LightSelection::Power(cdf) => {
+                // First entry of the CDF above u
+                let (mut low, mut high) = (0, cdf.len() - 1);
+                while low < high {
+                    let middle = (low + high) / 2;
+                    if cdf[middle] <= u { low = middle + 1; } else { high = middle; }
+                }
+
+                let pmf = if low == 0 { cdf[0] } else { cdf[low] - cdf[low - 1] };
+                if pmf > 0. { Some((low, pmf)) } else { None }
+            },
+            LightSelection::Tree(tree) => tree.pick(point, normal, u),
+        }
+    }
+
+    /// Sample a point on some light for direct lighting at a point with the given normal
+    pub fn sample(&self, point: Vec3, normal: Vec3, sampler: &mut dyn Sampler) -> Option<LightSample> {
+        let (index, pmf) = self.pick(point, normal, random_float(sampler))?;
+        let mut sample = self.emitters[index].sample(point, sampler)?;
+        sample.pdf *= pmf;
+
+        Some(sample)
+    }
+}
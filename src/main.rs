@@ -10,6 +10,21 @@ use cgmath::prelude::*;
 //use gl;
 
 pub mod render_gl;
+mod sampler;
+mod vector;
+mod transform;
+mod camera_model;
+use camera_model::{CameraModel, Fov};
+mod camera_path;
+use camera_path::{CameraPath, Keyframe};
+
+fn to_cgmath(v: vector::Vec3) -> cgmath::Vector3<f32> {
+    cgmath::vec3(v.x, v.y, v.z)
+}
+
+fn from_cgmath(v: cgmath::Vector3<f32>) -> vector::Vec3 {
+    vector::Vec3::new(v.x, v.y, v.z)
+}
 
 fn next_power_of_2(x: i32) -> i32 {
     let mut y = x;
@@ -24,6 +39,18 @@ fn next_power_of_2(x: i32) -> i32 {
 
 }
 
+/// Storage for an image the compute shader reads and writes
+fn create_image_texture(width: i32, height: i32, format: gl::types::GLenum) -> gl::types::GLuint {
+    let mut id: gl::types::GLuint = 0;
+    unsafe {
+        gl::GenTextures(1, &mut id);
+        gl::BindTexture(gl::TEXTURE_2D, id);
+        gl::TexStorage2D(gl::TEXTURE_2D, 1, format, width, height);
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+    }
+    id
+}
+
 fn main() {
     // Starting SDL
     let (mut window_w, mut window_h) = (1200i32, 650i32);
@@ -158,6 +185,11 @@ fn main() {
         gl::BindTexture(gl::TEXTURE_2D, 0);
     }
 
+    // Running sums for progressive rendering; only read once accumulated_frames is above 0
+    let mut accumulation_id = create_image_texture(window_w, window_h, gl::RGBA32F);
+    let mut moments_id = create_image_texture(window_w, window_h, gl::R32F);
+    let focus_pick_id = create_image_texture(1, 1, gl::R32F);
+
 
     // Setup some random opengl stuff
     unsafe {
@@ -188,36 +220,101 @@ fn main() {
 
     !!!! */
 
-    let mut position: cgmath::Vector3<f32> = 3. * cgmath::Vector3::<f32>::unit_z();
+    // The same camera description the CPU renderer takes; the controls below move it about
+    let mut camera = CameraModel::new(3. * vector::Vec3::Z, -vector::Vec3::Z, vector::Vec3::Y, Fov::Vertical(vector::PI / 2.), 2., 0.);
+
+    let mut position: cgmath::Vector3<f32> = to_cgmath(camera.position);
     let position_uniform = render_gl::Uniform::new("origin", comp_program.id()).unwrap();
 
-    let mut direction: cgmath::Vector3<f32>; // = -cgmath::Vector3::<f32>::unit_z();
+    let mut direction: cgmath::Vector3<f32>;
     let direction_uniform = render_gl::Uniform::new("forward", comp_program.id()).unwrap();
-    let mut horizontal_angle: f32 = 0.;
-    let mut vertical_angle: f32 = 0.;
+    let (mut horizontal_angle, mut vertical_angle) = camera.angles();
     
-    let up: cgmath::Vector3<f32> = cgmath::Vector3::<f32>::unit_y();
+    let up: cgmath::Vector3<f32> = to_cgmath(camera.global_up);
     let up_uniform = render_gl::Uniform::new("up", comp_program.id()).unwrap();
 
+    // Tangents of half the FOV across and up the window
+    let film_extent_uniform = render_gl::Uniform::new("film_extent", comp_program.id()).unwrap();
 
     let focus_dist_uniform = render_gl::Uniform::new("focus_dist", comp_program.id()).unwrap();
-    let mut focus_dist: f32 = 2.;
+    let mut focus_dist: f32 = camera.focus_distance;
 
     let focus_radius_uniform = render_gl::Uniform::new("focus_radius", comp_program.id()).unwrap();
-    let mut focus_radius: f32 = 0.;
+    let mut focus_radius: f32 = camera.aperture;
+
+    // Right clicking a pixel focuses on whatever is under it; the shader measures the distance and
+    // focus_dist follows it over about focus_pull_time milliseconds (0 snaps straight there)
+    let pick_pixel_uniform = render_gl::Uniform::new("pick_pixel", comp_program.id()).unwrap();
+    let mut pick_pixel: Option<(i32, i32)> = None;
+    let mut focus_target: Option<f32> = None;
+    let focus_pull_time: f32 = 150.;
+
+    // K changes the number of aperture blades (0 is round) and N switches anamorphic squeeze
+    let aperture_blades_uniform = render_gl::Uniform::new("aperture_blades", comp_program.id()).unwrap();
+    let mut aperture_blades: u32 = 0;
+    let aperture_rotation_uniform = render_gl::Uniform::new("aperture_rotation", comp_program.id()).unwrap();
+    let aperture_rotation: f32 = 0.;
+    let aperture_squeeze_uniform = render_gl::Uniform::new("aperture_squeeze", comp_program.id()).unwrap();
+    let mut aperture_squeeze: f32 = 1.;
+
+    // 0 perspective, 1 orthographic, 2 equirectangular, 3 cube face, 4 and 5 fisheyes; O cycles through them,
+    // the wheel zooms the orthographic view and F picks the cube face
+    let projection_uniform = render_gl::Uniform::new("projection", comp_program.id()).unwrap();
+    let mut projection: u32 = 0;
+    let cube_face_uniform = render_gl::Uniform::new("cube_face", comp_program.id()).unwrap();
+    let mut cube_face: u32 = 0;
+    let view_width_uniform = render_gl::Uniform::new("view_width", comp_program.id()).unwrap();
+    let mut view_width: f32 = 4.;
+
+    // 0 mono, 1 side by side, 2 top and bottom (ODS with the equirectangular projection), 3 red-cyan
+    // anaglyph; V cycles through them. The eyes converge on the focal plane
+    let stereo_uniform = render_gl::Uniform::new("stereo", comp_program.id()).unwrap();
+    let mut stereo: u32 = 0;
+    let interocular_uniform = render_gl::Uniform::new("interocular", comp_program.id()).unwrap();
+    let interocular: f32 = 0.1;
+    let convergence_uniform = render_gl::Uniform::new("convergence", comp_program.id()).unwrap();
+
+    // T and G tilt the plane of focus, Y and H shift the film up and down, and J curves the focal surface
+    let film_shift_uniform = render_gl::Uniform::new("film_shift", comp_program.id()).unwrap();
+    let mut film_shift: f32 = 0.;
+    let focus_tilt_uniform = render_gl::Uniform::new("focus_tilt", comp_program.id()).unwrap();
+    let mut focus_tilt: f32 = 0.;
+    let focus_curvature_uniform = render_gl::Uniform::new("focus_curvature", comp_program.id()).unwrap();
+    let mut focus_curvature: f32 = 0.;
 
     let mut canvas_side;
     let mut canvas_up;
 
     let speed = 0.005; // in units per millisecond
-    let mut time: usize = 0;
     let mut new_time: usize;
     let mut current_time: usize = 0;
     let mut frame_time: usize;
     let frame_uniform = render_gl::Uniform::new("frame", comp_program.id()).unwrap();
+
+    // 0 for white noise, 1 for Sobol, 2 for blue noise; B cycles through them
+    let sampler_mode_uniform = render_gl::Uniform::new("sampler_mode", comp_program.id()).unwrap();
+    let mut sampler_mode: u32 = 2;
+
+    // Frames since the view last changed; pixels whose noise is under the threshold stop being traced
+    let accumulated_frames_uniform = render_gl::Uniform::new("accumulated_frames", comp_program.id()).unwrap();
+    let mut accumulated_frames: u32 = 0;
+    let adaptive_threshold_uniform = render_gl::Uniform::new("adaptive_threshold", comp_program.id()).unwrap();
+    let adaptive_threshold: f32 = 0.01;
+    let mut frames: u32 = 0;
+
+    // R starts recording a camera path and stops it again, saving it for render_path in old.rs
+    let mut recording: Option<CameraPath> = None;
+    let mut recording_start: usize = 0;
+    let mut last_keyframe: usize = 0;
+    let keyframe_interval: usize = 250; // in milliseconds
+    let path_file = "camera_path.txt";
+    // Grouped since tuples only compare up to twelve long
+    let mut last_view = ((position, horizontal_angle, vertical_angle), (focus_dist, focus_radius, film_shift, focus_tilt, focus_curvature),
+                         (sampler_mode, projection, view_width, cube_face, aperture_blades, aperture_squeeze, stereo));
     
 
-    let keys_list = vec![Keycode::W, Keycode::A, Keycode::S, Keycode::D, Keycode::Space, Keycode::C, Keycode::Up, Keycode::Down, Keycode::Left, Keycode::Right];
+    let keys_list = vec![Keycode::W, Keycode::A, Keycode::S, Keycode::D, Keycode::Space, Keycode::C, Keycode::Up, Keycode::Down, Keycode::Left, Keycode::Right,
+                         Keycode::T, Keycode::G, Keycode::Y, Keycode::H];
     let mut keys_down: HashSet<Keycode> = HashSet::new();
     let mut focus = false;
 
@@ -233,6 +330,48 @@ fn main() {
                     break 'main;
                 }
 
+                Event::KeyDown { keycode: Some(Keycode::B), repeat: false, .. } => {
+                    sampler_mode = (sampler_mode + 1) % 3;
+                }
+
+                Event::KeyDown { keycode: Some(Keycode::O), repeat: false, .. } => {
+                    projection = (projection + 1) % 6;
+                }
+
+                Event::KeyDown { keycode: Some(Keycode::F), repeat: false, .. } => {
+                    cube_face = (cube_face + 1) % 6;
+                }
+
+                Event::KeyDown { keycode: Some(Keycode::R), repeat: false, .. } => {
+                    if let Some(mut path) = recording.take() {
+                        path.record(Keyframe::from_model((current_time - recording_start) as f32 / 1000., &camera));
+                        match path.save(std::path::Path::new(path_file)) {
+                            Ok(()) => println!("Saved {} keyframes to {}", path.keyframes.len(), path_file),
+                            Err(e) => println!("Couldn't save the camera path: {}", e),
+                        }
+                    } else {
+                        println!("Recording camera path");
+                        recording = Some(CameraPath::new());
+                        recording_start = current_time;
+                    }
+                }
+
+                Event::KeyDown { keycode: Some(Keycode::J), repeat: false, .. } => {
+                    focus_curvature = 1. - focus_curvature;
+                }
+
+                Event::KeyDown { keycode: Some(Keycode::V), repeat: false, .. } => {
+                    stereo = (stereo + 1) % 4;
+                }
+
+                Event::KeyDown { keycode: Some(Keycode::K), repeat: false, .. } => {
+                    aperture_blades = match aperture_blades { 0 => 5, 5 => 6, 6 => 8, _ => 0 };
+                }
+
+                Event::KeyDown { keycode: Some(Keycode::N), repeat: false, .. } => {
+                    aperture_squeeze = if aperture_squeeze == 1. { 2. } else { 1. };
+                }
+
                 Event::KeyDown { keycode: Some(x), repeat: false, .. } => {
                     if keys_list.contains(&x) { keys_down.insert(x); };
                 }
@@ -254,8 +393,20 @@ fn main() {
                 	}
                 }
 
-                Event::MouseWheel { direction: dir, .. } => {
-                    focus_dist += dir.to_ll() as f32 / 10.;
+                Event::MouseButtonUp { mouse_btn: sdl2::mouse::MouseButton::Right, x, y, .. } => {
+                    // SDL counts down from the top, the image up from the bottom
+                    if !focus {
+                        pick_pixel = Some((x, window_h - 1 - y));
+                    }
+                }
+
+                Event::MouseWheel { direction: dir, y, .. } => {
+                    if projection == 1 {
+                        view_width *= 0.9f32.powi(y);
+                    } else {
+                        focus_dist += dir.to_ll() as f32 / 10.;
+                        focus_target = None;
+                    }
                 }
 
                 Event::Window { win_event, .. } => {
@@ -285,6 +436,13 @@ fn main() {
                             gl::BindTexture(gl::TEXTURE_2D, 0);
                         }
 
+                        unsafe {
+                            gl::DeleteTextures(1, &accumulation_id);
+                            gl::DeleteTextures(1, &moments_id);
+                        }
+                        accumulation_id = create_image_texture(window_w, window_h, gl::RGBA32F);
+                        moments_id = create_image_texture(window_w, window_h, gl::R32F);
+                        accumulated_frames = 0;
                     }
                 }
 
@@ -292,7 +450,7 @@ fn main() {
             }}
             
 
-            direction = cgmath::vec3(horizontal_angle.sin(), -vertical_angle.sin(), -horizontal_angle.cos() * vertical_angle.cos());
+            direction = to_cgmath(CameraModel::from_angles(horizontal_angle, vertical_angle));
             canvas_side = up.cross(direction).normalize(); //norm cross up direction
             canvas_up = direction.cross(canvas_side);
 
@@ -320,8 +478,19 @@ fn main() {
 
             if keys_down.contains(&Keycode::Up) {
                 focus_dist += 0.01 * frame_time as f32;
+                focus_target = None;
             } else if keys_down.contains(&Keycode::Down) {
                 focus_dist -= 0.01 * frame_time as f32;
+                focus_target = None;
+            }
+
+            if let Some(target) = focus_target {
+                let pull = if focus_pull_time > 0. { 1. - (-(frame_time as f32) / focus_pull_time).exp() } else { 1. };
+                focus_dist += pull * (target - focus_dist);
+                if (target - focus_dist).abs() < 0.001 {
+                    focus_dist = target;
+                    focus_target = None;
+                }
             }
             
             if keys_down.contains(&Keycode::Right) {
@@ -330,10 +499,41 @@ fn main() {
                 focus_radius -= 0.001 * frame_time as f32;
             }
 
+            if keys_down.contains(&Keycode::T) {
+                focus_tilt += 0.0005 * frame_time as f32;
+            } else if keys_down.contains(&Keycode::G) {
+                focus_tilt -= 0.0005 * frame_time as f32;
+            }
+            focus_tilt = focus_tilt.clamp(-1.4, 1.4);
+
+            if keys_down.contains(&Keycode::Y) {
+                film_shift += 0.0005 * frame_time as f32;
+            } else if keys_down.contains(&Keycode::H) {
+                film_shift -= 0.0005 * frame_time as f32;
+            }
+
             focus_dist = focus_dist.max(0.);
             focus_radius = focus_radius.max(0.);
 
-            time += frame_time;
+            camera.position = from_cgmath(position);
+            camera.looking = from_cgmath(direction);
+            camera.focus_distance = focus_dist;
+            camera.aperture = focus_radius;
+
+            if let Some(path) = &mut recording {
+                if path.keyframes.is_empty() || current_time >= last_keyframe + keyframe_interval {
+                    path.record(Keyframe::from_model((current_time - recording_start) as f32 / 1000., &camera));
+                    last_keyframe = current_time;
+                }
+            }
+
+            // Start accumulating again whenever anything about the view changes
+            let view = ((position, horizontal_angle, vertical_angle), (focus_dist, focus_radius, film_shift, focus_tilt, focus_curvature),
+                        (sampler_mode, projection, view_width, cube_face, aperture_blades, aperture_squeeze, stereo));
+            if view != last_view {
+                accumulated_frames = 0;
+                last_view = view;
+            }
 
             unsafe {
                 gl::Clear(gl::COLOR_BUFFER_BIT);
@@ -341,15 +541,39 @@ fn main() {
 
             comp_program.set_used();
 
-            position_uniform.push_3f(position);
-            direction_uniform.push_3f(direction);
-            up_uniform.push_3f(up);
-            frame_uniform.push_1ui(time as u32);
-            focus_dist_uniform.push_1f(focus_dist);
-            focus_radius_uniform.push_1f(focus_radius);
+            // Each eye gets half the window side by side or top and bottom
+            let (eye_w, eye_h) = match stereo { 1 => (window_w / 2, window_h), 2 => (window_w, window_h / 2), _ => (window_w, window_h) };
+            let (tan_half_width, tan_half_height) = camera.tan_half_extents(eye_w as usize, eye_h as usize);
+            position_uniform.push_3f(to_cgmath(camera.position));
+            direction_uniform.push_3f(to_cgmath(camera.looking));
+            up_uniform.push_3f(to_cgmath(camera.global_up));
+            film_extent_uniform.push_2f(tan_half_width, tan_half_height);
+            frame_uniform.push_1ui(frames);
+            accumulated_frames_uniform.push_1ui(accumulated_frames);
+            adaptive_threshold_uniform.push_1f(adaptive_threshold);
+            sampler_mode_uniform.push_1ui(sampler_mode);
+            focus_dist_uniform.push_1f(camera.focus_distance);
+            focus_radius_uniform.push_1f(camera.aperture);
+            aperture_blades_uniform.push_1ui(aperture_blades);
+            aperture_rotation_uniform.push_1f(aperture_rotation);
+            aperture_squeeze_uniform.push_1f(aperture_squeeze);
+            projection_uniform.push_1ui(projection);
+            view_width_uniform.push_1f(view_width);
+            cube_face_uniform.push_1ui(cube_face);
+            stereo_uniform.push_1ui(stereo);
+            film_shift_uniform.push_2f(0., film_shift);
+            focus_tilt_uniform.push_2f(0., focus_tilt);
+            focus_curvature_uniform.push_1f(focus_curvature);
+            interocular_uniform.push_1f(interocular);
+            convergence_uniform.push_1f(camera.focus_distance.max(0.01));
+            let (pick_x, pick_y) = pick_pixel.unwrap_or((-1, -1));
+            pick_pixel_uniform.push_2i(pick_x, pick_y);
 
             unsafe {
                 gl::BindImageTexture(0, tex_id, 0, gl::FALSE, 0, gl::WRITE_ONLY, gl::RGBA32F);
+                gl::BindImageTexture(1, accumulation_id, 0, gl::FALSE, 0, gl::READ_WRITE, gl::RGBA32F);
+                gl::BindImageTexture(2, moments_id, 0, gl::FALSE, 0, gl::READ_WRITE, gl::R32F);
+                gl::BindImageTexture(3, focus_pick_id, 0, gl::FALSE, 0, gl::WRITE_ONLY, gl::R32F);
             }
 
             let work_x = next_power_of_2(window_w);
@@ -359,10 +583,26 @@ fn main() {
                 gl::DispatchCompute((work_x / work_group_size[0]) as u32, (work_y / work_group_size[1]) as u32, 1);
 
                 gl::BindImageTexture(0, 0, 0, gl::FALSE, 0, gl::READ_WRITE, gl::RGBA32F);
-                gl::MemoryBarrier(gl::SHADER_IMAGE_ACCESS_BARRIER_BIT);
+                gl::BindImageTexture(1, 0, 0, gl::FALSE, 0, gl::READ_WRITE, gl::RGBA32F);
+                gl::BindImageTexture(2, 0, 0, gl::FALSE, 0, gl::READ_WRITE, gl::R32F);
+                gl::BindImageTexture(3, 0, 0, gl::FALSE, 0, gl::READ_WRITE, gl::R32F);
+                gl::MemoryBarrier(gl::SHADER_IMAGE_ACCESS_BARRIER_BIT | gl::TEXTURE_UPDATE_BARRIER_BIT);
                 gl::UseProgram(0);
             }
 
+            // Read back what the pick found; a miss leaves the focus alone
+            if pick_pixel.take().is_some() {
+                let mut distance: f32 = 0.;
+                unsafe {
+                    gl::BindTexture(gl::TEXTURE_2D, focus_pick_id);
+                    gl::GetTexImage(gl::TEXTURE_2D, 0, gl::RED, gl::FLOAT, &mut distance as *mut f32 as *mut gl::types::GLvoid);
+                    gl::BindTexture(gl::TEXTURE_2D, 0);
+                }
+                if distance > 0. {
+                    focus_target = Some(distance);
+                }
+            }
+
             quad_program.set_used();
             
             unsafe {
@@ -380,6 +620,9 @@ fn main() {
             
             window.gl_swap_window();
 
+            frames += 1;
+            accumulated_frames += 1;
+
             //println!("Frame time: {}ms", frame_time);
         }
     }
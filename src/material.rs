@@ -16,6 +16,7 @@ pub enum Material {
     LambertCos(Float), // Uses cosine weighted sampling
     Mirror(Float), // Albedo here for some reason
     Glass(Float), // Glass with refractive index
+    Dispersive(Ior), // Glass whose refractive index depends on wavelength
     Light(Float), // Lights like this also absorb all incoming light
     LightUni(Float), // A light which only emits on the normal facing side
     LightCos(Float), // Cosine weighted light, which is also unidirectional
@@ -23,6 +24,30 @@ pub enum Material {
     Test,
 }
 
+/// A wavelength dependent refractive index
+#[derive(Copy, Clone)]
+pub enum Ior {
+    Cauchy(Float, Float), // n = A + B / lambda^2, lambda in micrometres
+    Sellmeier([Float; 3], [Float; 3]), // B and C coefficients, C in square micrometres
+}
+
+impl Ior {
+    /// Sodium D line; where the plain refractive index is quoted, used when not rendering spectrally
+    pub const D_LINE: Float = 587.6;
+
+    pub const BK7: Ior = Ior::Sellmeier([1.03961212, 0.231792344, 1.01046945], [0.00600069867, 0.0200179144, 103.560653]);
+    pub const DIAMOND: Ior = Ior::Sellmeier([4.3356, 0.3306, 0.], [0.01124, 0.03063, 0.]);
+
+    /// Refractive index at a wavelength in nm
+    pub fn at(self, lambda: Float) -> Float {
+        let l2 = (lambda / 1000.) * (lambda / 1000.);
+        match self {
+            Ior::Cauchy(a, b) => a + b / l2,
+            Ior::Sellmeier(b, c) => (1. + b[0] * l2 / (l2 - c[0]) + b[1] * l2 / (l2 - c[1]) + b[2] * l2 / (l2 - c[2])).sqrt(),
+        }
+    }
+}
+
 /// Schlick approximation or something
 pub fn schlick(cos: Float, n_dielectric: Float) -> Float {
     let r0 = ((1.-n_dielectric)/(1.+n_dielectric)).powf(2.);
@@ -4,7 +4,10 @@ use super::shape::*;
 use super::material::Material;
 use super::vector::*;
 use super::colour::*;
+use super::sampler::Sampler;
+use super::transform::{Motion, Matrix4};
 use obj::Obj;
+use std::sync::Arc;
 
 /// Information about an intersection with an object
 #[derive(Copy, Clone)] // This is only required for the boxed vector of objects
@@ -18,7 +21,7 @@ pub struct ObjectHit {
 
 /// An intersectable object with some look (material, colour)
 pub trait Object {
-    fn intersect(&self, ray: Ray) -> Option<ObjectHit>;
+    fn intersect(&self, ray: Ray, sampler: &mut dyn Sampler) -> Option<ObjectHit>;
 }
 
 /// Object type which holds a single primitive
@@ -29,7 +32,7 @@ pub struct GeneralObject<T: Shape + Sync + Send> {
 }
 
 impl<T> Object for GeneralObject<T> where T: Shape + Sync + Send{
-    fn intersect(&self, ray: Ray) -> Option<ObjectHit> {
+    fn intersect(&self, ray: Ray, _sampler: &mut dyn Sampler) -> Option<ObjectHit> {
         match self.shape.intersect(ray) {
             Some(vals) => Some(ObjectHit{
                 point: vals.point, normal: vals.norm, dist: vals.dist,
@@ -40,6 +43,67 @@ impl<T> Object for GeneralObject<T> where T: Shape + Sync + Send{
     }
 }
 
+/// Another object that moves; it is wherever its motion puts it at the time of each ray
+pub struct MovingObject<O: Object + Sync + Send> {
+    pub object: O,
+    pub motion: Motion,
+}
+
+impl<O> Object for MovingObject<O> where O: Object + Sync + Send {
+    fn intersect(&self, ray: Ray, sampler: &mut dyn Sampler) -> Option<ObjectHit> {
+        let transform = self.motion.at(ray.time);
+
+        // The direction gets scaled going into object space; normalise it and scale the distance back after
+        let local_direction = transform.inverse_vector(ray.direction);
+        let stretch = local_direction.norm();
+        let local = ray.spawn(transform.inverse_point(ray.origin), (1. / stretch) * local_direction);
+
+        self.object.intersect(local, sampler).map(|hit| ObjectHit {
+            point: transform.point(hit.point),
+            normal: transform.normal(hit.normal),
+            dist: hit.dist / stretch,
+            ..hit
+        })
+    }
+}
+
+/// A shared object put somewhere by a transform, so one mesh can be placed many times without
+/// copying it
+pub struct Instance<O: Object + Sync + Send> {
+    pub object: Arc<O>,
+    transform: Matrix4,
+    inverse: Matrix4,
+    normal_transform: Matrix4, // Inverse transpose, so normals stay perpendicular under uneven scale
+}
+
+impl<O> Instance<O> where O: Object + Sync + Send {
+    pub fn new(object: Arc<O>, transform: Matrix4) -> Result<Instance<O>, String> {
+        let inverse = transform.inverse().ok_or("Instance transform can't be inverted")?;
+        Ok(Instance {
+            object: object,
+            transform: transform,
+            inverse: inverse,
+            normal_transform: inverse.transpose(),
+        })
+    }
+}
+
+impl<O> Object for Instance<O> where O: Object + Sync + Send {
+    fn intersect(&self, ray: Ray, sampler: &mut dyn Sampler) -> Option<ObjectHit> {
+        // As MovingObject: normalise the direction in object space and scale the distance back
+        let local_direction = self.inverse.vector(ray.direction);
+        let stretch = local_direction.norm();
+        let local = ray.spawn(self.inverse.point(ray.origin), (1. / stretch) * local_direction);
+
+        self.object.intersect(local, sampler).map(|hit| ObjectHit {
+            point: self.transform.point(hit.point),
+            normal: self.normal_transform.vector(hit.normal).normalise(),
+            dist: hit.dist / stretch,
+            ..hit
+        })
+    }
+}
+
 /// A list of triangles basically
 //pub struct TriangleCollection {
 pub struct ObjectCollection<T: Shape + Sync + Send> {
@@ -129,7 +193,7 @@ impl ObjectCollection<Triangle> {
 
 //impl Object for TriangleCollection {
 impl<T> Object for ObjectCollection<T> where T: Shape + Sync + Send {
-    fn intersect(&self, ray: Ray) -> Option<ObjectHit> {
+    fn intersect(&self, ray: Ray, _sampler: &mut dyn Sampler) -> Option<ObjectHit> {
         let mut hit: Option<Hit> = None;
 
         for shape in self.shapes.iter() {
@@ -154,11 +218,11 @@ impl<T> Object for ObjectCollection<T> where T: Shape + Sync + Send {
 
 
 impl Object for Vec<Box<dyn Object + Sync + Send>> where {
-    fn intersect(&self, ray: Ray) -> Option<ObjectHit> {
+    fn intersect(&self, ray: Ray, sampler: &mut dyn Sampler) -> Option<ObjectHit> {
         let mut object_hit: Option<ObjectHit> = None;
 
         for object in self.iter() {
-            if let Some(candidate_hit) = object.intersect(ray) {
+            if let Some(candidate_hit) = object.intersect(ray, sampler) {
                 match object_hit {
                     None => object_hit = Some(candidate_hit),
                     Some(previous) => if candidate_hit.dist < previous.dist {
@@ -173,10 +237,22 @@ impl Object for Vec<Box<dyn Object + Sync + Send>> where {
 }
 
 
-/// Convert an OBJ file into a large amount of triangles
-pub fn convert_objects_to_polygons(obj: &Obj<obj::SimplePolygon>) -> Vec<Triangle> {
-        let mut polygons = vec![];
+/// How much a triangle's normal counts towards the vertex normal at corner k: the angle
+/// there times the area
+fn corner_weight(points: [Vec3; 3], k: usize) -> Float {
+    let e1 = points[(k + 1) % 3] - points[k];
+    let e2 = points[(k + 2) % 3] - points[k];
+    let lengths = e1.norm() * e2.norm();
+    if lengths < EPS { return 0.; }
 
+    let angle = (dot(e1, e2) / lengths).clamp(-1., 1.).acos();
+    angle * cross(e1, e2).norm() / 2.
+}
+
+/// Convert an OBJ file into a large amount of triangles
+/// They are smooth shaded with the file's vn normals, or failing those with the normals of the
+/// faces round each vertex that are within the crease angle (radians) of each other; 0 is flat
+pub fn convert_objects_to_polygons(obj: &Obj<obj::SimplePolygon>, crease_angle: Float) -> Vec<Triangle> {
     let make_vector = |floats: &[f32; 3]| {
         Vec3 {
             x: floats[0],
@@ -185,36 +261,58 @@ pub fn convert_objects_to_polygons(obj: &Obj<obj::SimplePolygon>) -> Vec<Triangl
         }
     };
 
-    let make_polygon = |index1, index2, index3| {
-        let obj::IndexTuple(index1, _, _) = index1;
-        let obj::IndexTuple(index2, _, _) = index2;
-        let obj::IndexTuple(index3, _, _) = index3;
-
-        let vertex1 = make_vector(&obj.position[index1]);
-        let vertex2 = make_vector(&obj.position[index2]);
-        let vertex3 = make_vector(&obj.position[index3]);
-
-        //let a = vertex2.sub(vertex1);
-        //let b = vertex3.sub(vertex1);
-
-        //let normal = a.cross(b).normalize();
-
-        Triangle::new(vertex1, vertex2, vertex3)
-    };
-
+    // Fan out the polygons, keeping the indices for the normals
+    let mut faces: Vec<[obj::IndexTuple; 3]> = vec![];
     for object in &obj.objects {
         for group in &object.groups {
             for poly in &group.polys {
                 let index1 = poly[0];
                 for others in poly[1..].windows(2) {
-                    let polygon = make_polygon(index1, others[0], others[1]);
-                    polygons.push(polygon);
+                    faces.push([index1, others[0], others[1]]);
                 }
             }
         }
     }
 
-    return polygons;
+    let points: Vec<[Vec3; 3]> = faces.iter().map(|face| {
+        [make_vector(&obj.position[face[0].0]), make_vector(&obj.position[face[1].0]), make_vector(&obj.position[face[2].0])]
+    }).collect();
+    let flat: Vec<Triangle> = points.iter().map(|p| Triangle::new(p[0], p[1], p[2])).collect();
+
+    if crease_angle <= 0. && faces.iter().all(|face| face.iter().all(|index| index.2.is_none())) {
+        return flat;
+    }
+
+    // The faces round each vertex
+    let mut adjacent: Vec<Vec<(usize, usize)>> = vec![vec![]; obj.position.len()];
+    for (i, face) in faces.iter().enumerate() {
+        for k in 0..3 {
+            adjacent[face[k].0].push((i, k));
+        }
+    }
+
+    let cos_crease = crease_angle.cos();
+    faces.iter().enumerate().map(|(i, face)| {
+        let triangle = &flat[i];
+        let mut normals = [triangle.normal; 3];
+
+        for k in 0..3 {
+            normals[k] = match face[k].2 {
+                Some(n) => make_vector(&obj.normal[n]),
+                None => {
+                    let mut sum = Vec3::ZERO;
+                    for &(j, corner) in adjacent[face[k].0].iter() {
+                        if dot(flat[j].normal, triangle.normal) >= cos_crease {
+                            sum += corner_weight(points[j], corner) * flat[j].normal;
+                        }
+                    }
+                    if sum.norm() < EPS { triangle.normal } else { sum.normalise() }
+                },
+            };
+        }
+
+        Triangle::with_normals(triangle.a, triangle.b, triangle.c, normals)
+    }).collect()
 }
 
 
@@ -227,8 +325,8 @@ pub struct MediumObject {
 }
 
 impl Object for MediumObject {
-    fn intersect(&self, ray: Ray) -> Option<ObjectHit> {
-        let dist = -(-random_float()).ln_1p() / self.density;
+    fn intersect(&self, ray: Ray, sampler: &mut dyn Sampler) -> Option<ObjectHit> {
+        let dist = -(-random_float(sampler)).ln_1p() / self.density;
 
         Some(ObjectHit {
             point: ray.eval(dist),
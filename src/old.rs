@@ -1,7 +1,6 @@
 use std::path::Path;
 use std::sync::Arc;
 
-use image::{ImageBuffer, RgbImage};
 mod vector;
 use vector::*;
 
@@ -12,48 +11,206 @@ mod material;
 use material::*;
 
 mod shape;
-use shape::{Triangle, Sphere, Plane};
+use shape::{Triangle, Sphere, Plane, Disk, Cylinder, Cone, Quad, AABox};
+
+mod sdf;
+use sdf::{Sdf, SdfShape};
 
 mod object;
 use object::*;
 
+mod transform;
+
+mod camera_model;
+use camera_model::{CameraModel, Fov};
+
+mod lens;
+
+mod camera_path;
+use camera_path::CameraPath;
+
 mod camera;
 use camera::{Camera, SimpleAACamera, SimpleCamera, DOFCamera};
 
+mod ies;
+
+mod light;
+use light::*;
+
+mod sampler;
+use sampler::{Sampler, SamplerKind};
+
+mod film;
+use film::{Film, FilmPixel, Filter};
+
+mod spectrum;
+use spectrum::*;
+
 use minifb::{Key, Window, WindowOptions};
 
 use rayon::prelude::*;
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+
+
+
+/// Give each pixel of the film as many more samples as asked for
+fn add_samples<O, C, F>(film: &mut Film, scene: &O, lights: &LightSampler, camera: &C, integrator: &Integrator, count: F)
+    where O: Object + Sync, C: Camera + Sync, F: Fn(&FilmPixel) -> usize + Sync {
+    let (width, height) = (film.width, film.height);
+
+    // A row at a time; the samples are traced in parallel and splatted afterwards, since
+    // they land on neighbouring pixels too
+    for y in 0..height {
+        let pixels = &film.pixels;
+        let row: Vec<(usize, usize, Float, Float, Colour)> = (0..width).into_par_iter().flat_map(|x| {
+            let pixel = pixels[x + y * width];
+
+            (pixel.samples..pixel.samples + count(&pixel)).map(move |sample| {
+                let (film_x, film_y, colour) = integrator.sample_pixel(scene, lights, camera, x, y, width, height, sample);
+                (x + y * width, sample, film_x, film_y, colour)
+            }).collect::<Vec<_>>()
+        }).collect();
+
+        for (i, sample, film_x, film_y, colour) in row {
+            film.pixels[i].add(colour);
+            film.splat(film_x, film_y, colour, sample % 2 == 0);
+        }
+    }
+}
+
+
+
+/// When an offline render stops; whichever comes first
+#[derive(Clone)]
+struct RenderLimits {
+    time: Option<Duration>,
+    error: Option<Float>, // Relative error estimated from the half-buffers
+    max_samples: usize, // Per pixel
+    batch: usize, // Samples per pixel between checks
+}
 
+impl RenderLimits {
+    /// Just a fixed number of samples
+    fn samples(samples: usize) -> RenderLimits {
+        RenderLimits { time: None, error: None, max_samples: samples, batch: samples }
+    }
+}
 
+/// Everything about an offline render but the scene and the camera
+#[derive(Clone)]
+struct RenderSettings {
+    width: usize,
+    height: usize,
+    limits: RenderLimits,
+    filter: Filter,
+    integrator: Integrator,
+}
 
+fn render<O: Object + Sync, C: Camera + Sync>(scene: &O, lights: &LightSampler, camera: &C, settings: &RenderSettings, filename: String) {
+    let film = render_film(scene, lights, camera, settings);
+    film.save(&filename).unwrap();
+}
+
+/// Render a recorded camera path at a frame rate to numbered images (prefix0000.png, prefix0001.png, ...).
+/// Limited only by samples every frame gets the same number, and with a fixed seed it comes out the same every time
+fn render_path<O: Object + Sync>(scene: &O, lights: &LightSampler, path: &CameraPath, fov: Fov, fps: Float,
+                                 settings: &RenderSettings, prefix: &str) -> Result<(), String> {
+    let frames = (path.duration() * fps).floor() as usize + 1;
+    let start = path.keyframes.first().ok_or("The camera path has no keyframes")?.time;
+
+    for frame in 0..frames {
+        let model = path.at(start + frame as Float / fps, fov).ok_or("The camera path has no keyframes")?;
+        let camera = DOFCamera::from_model(&model, settings.width, settings.height);
+        println!("Frame {} of {}", frame + 1, frames);
+        render(scene, lights, &camera, settings, format!("{}{:04}.png", prefix, frame));
+    }
+    Ok(())
+}
+
+/// Red-cyan anaglyph from a camera for each eye, e.g. from StereoRig::eye
+fn render_anaglyph<O: Object + Sync, C: Camera + Sync>(scene: &O, lights: &LightSampler, left: &C, right: &C,
+                                                      settings: &RenderSettings, filename: String) {
+    let left = render_film(scene, lights, left, settings);
+    let right = render_film(scene, lights, right, settings);
+    Film::anaglyph(&left, &right).save(&filename).unwrap();
+}
+
+fn render_film<O: Object + Sync, C: Camera + Sync>(scene: &O, lights: &LightSampler, camera: &C, settings: &RenderSettings) -> Film {
+    let start = Instant::now();
+    let limits = &settings.limits;
 
-fn render<O: Object, C: Camera>(scene: &O, camera: &C, width: usize, height: usize, samples: usize, depth: usize, filename: String) {
     // The final image
-    let mut result: RgbImage = ImageBuffer::new(width as u32, height as u32);
+    let mut film = Film::new(settings.width, settings.height, settings.filter);
+    let mut samples = 0;
+    let mut error = Float::INFINITY;
 
+    while samples < limits.max_samples {
+        let batch = limits.batch.max(1).min(limits.max_samples - samples);
+        add_samples(&mut film, scene, lights, camera, &settings.integrator, |_| batch);
+        samples += batch;
+
+        error = film.estimated_error();
+        if limits.error.is_some_and(|target| error <= target) { break };
+        if limits.time.is_some_and(|time| start.elapsed() >= time) { break };
+    }
+
+    println!("{} samples per pixel, estimated error {:.4}, {:.1} seconds", samples, error, start.elapsed().as_secs_f32());
+
+    film
+}
 
-    let mut col; // = Colour::BLACK;
 
-    for (x, y, pixel) in result.enumerate_pixels_mut() {
-        col = Colour::BLACK;
-        
-        for _ in 0..samples {
-            col += trace(scene, camera.generate_ray(x as usize, y as usize, width, height), depth);
-        }
 
-        *pixel = (col / samples as Float).to_image_rgb();
+/// When to stop sampling a pixel
+struct Adaptive {
+    min_samples: usize, // Every pixel gets at least this many before its noise is trusted
+    batch: usize, // Samples added to each noisy pixel per round
+    max_samples: usize, // Per pixel
+    threshold: Float, // Relative standard error a pixel has to get under
+    budget: usize, // Samples for the whole image
+}
 
-        //*pixel = trace(&sphere, main_camera.generate_ray(x, y, width, height), 5).to_rgb();
+/// Render with more samples going to the pixels that are still noisy, until they all
+/// converge or the budget is spent; adaptive stands in for the settings' limits
+fn render_adaptive<O: Object + Sync, C: Camera + Sync>(scene: &O, lights: &LightSampler, camera: &C, settings: &RenderSettings,
+                                                      adaptive: &Adaptive, filename: String) {
+    let (width, height) = (settings.width, settings.height);
+    let mut film = Film::new(width, height, settings.filter);
+    let mut spent = 0;
+    let mut round = 0;
+
+    loop {
+        let batch = if round == 0 { adaptive.min_samples } else { adaptive.batch };
+        let remaining = adaptive.budget.saturating_sub(spent);
+
+        let active = film.pixels.iter().filter(|pixel| pixel.samples == 0
+            || (pixel.samples < adaptive.max_samples && pixel.relative_error() > adaptive.threshold)).count();
+        if active == 0 || remaining < active { break };
+
+        // Take fewer samples at the end rather than go over the budget
+        let batch = batch.min(remaining / active).max(1);
+
+        add_samples(&mut film, scene, lights, camera, &settings.integrator, |pixel| {
+            let done = pixel.samples > 0 && (pixel.samples >= adaptive.max_samples || pixel.relative_error() <= adaptive.threshold);
+            if done { 0 } else { batch }
+        });
+
+        spent += batch * active;
+        round += 1;
     }
 
-    result.save(filename).unwrap();
+    let converged = film.pixels.iter().filter(|pixel| pixel.relative_error() <= adaptive.threshold).count();
+    println!("{} samples ({:.1} per pixel), {} of {} pixels converged, estimated error {:.4}",
+             spent, spent as Float / (width * height) as Float, converged, width * height, film.estimated_error());
+
+    film.save(&filename).unwrap();
 }
 
 
 
-fn render_iter<O: Object, C: Camera>(scene: &O, camera: &C, width: usize, height: usize, samples: usize, depth: usize) {
+fn render_iter<O: Object, C: Camera>(scene: &O, lights: &LightSampler, camera: &C, width: usize, height: usize, samples: usize, integrator: &Integrator) {
     let mut buffer: Vec<u32> = vec![0; width*height];
 
     // The final image
@@ -62,8 +219,9 @@ fn render_iter<O: Object, C: Camera>(scene: &O, camera: &C, width: usize, height
         let x = i % width;
         let y = i / height;
 
-        for _ in 0..samples {
-            colour += trace(scene, camera.generate_ray(x, y, width, height), depth)
+        for sample in 0..samples {
+            let mut sampler = integrator.sampler(i, sample);
+            colour += integrator.radiance(scene, lights, camera.generate_ray(x, y, width, height, &mut *sampler), &mut *sampler)
         }
 
         buffer[i] = (colour / samples as Float).to_u32_rgb();
@@ -80,7 +238,7 @@ fn render_iter<O: Object, C: Camera>(scene: &O, camera: &C, width: usize, height
 
 
 
-fn render_window<O: Object, C: Camera>(scene: &O, camera: &C, width: usize, height: usize, depth: usize) {
+fn render_window<O: Object, C: Camera>(scene: &O, lights: &LightSampler, camera: &C, width: usize, height: usize, integrator: &Integrator) {
 
     let mut backbuffer = vec![Colour::ZERO; width * height];
     let mut buffer: Vec<u32> = vec![0; width * height];
@@ -96,7 +254,8 @@ fn render_window<O: Object, C: Camera>(scene: &O, camera: &C, width: usize, heig
         samples += 1;
         for x in 0..width {
             for y in 0..height {
-                backbuffer[x + y * width] += trace(scene, camera.generate_ray(x, y, width, height), depth);
+                let mut sampler = integrator.sampler(x + y * width, samples);
+                backbuffer[x + y * width] += integrator.radiance(scene, lights, camera.generate_ray(x, y, width, height, &mut *sampler), &mut *sampler);
                 buffer[x + y * width] = (backbuffer[x + y * width]/samples as Float).to_u32_rgb()
             }
         }
@@ -107,7 +266,7 @@ fn render_window<O: Object, C: Camera>(scene: &O, camera: &C, width: usize, heig
 }
 
 
-fn render_window_iter<O: Object + Sync + Send, C: Camera + Sync + Send>(scene: &O, camera: &mut C, width: usize, height: usize, depth: usize) {
+fn render_window_iter<O: Object + Sync + Send, C: Camera + Sync + Send>(scene: &O, lights: &LightSampler, camera: &mut C, width: usize, height: usize, integrator: &Integrator) {
     let mut pixels: Vec<(usize, usize)> = vec![];
 
     for y in 0..height {
@@ -171,13 +330,14 @@ fn render_window_iter<O: Object + Sync + Send, C: Camera + Sync + Send>(scene: &
         // Way with the pixels vector or whatever
         
         backbuffer = backbuffer.par_iter().zip(pixels.par_iter()).map(|(col, (x, y))| {
-            *col + trace(scene, camera.generate_ray(*x, *y, width, height), depth)
+            let mut sampler = integrator.sampler(x + y * width, samples);
+            *col + integrator.radiance(scene, lights, camera.generate_ray(*x, *y, width, height, &mut *sampler), &mut *sampler)
         }).collect();
         
 
         /*
         backbuffer = backbuffer.par_iter().enumerate().map(|(i, &col)| {
-            col + trace(scene, camera.generate_ray(i % width, i / width, width, height), depth)
+            col + integrator.radiance(scene, lights, camera.generate_ray(i % width, i / width, width, height))
         }).collect();
         */
 
@@ -198,8 +358,9 @@ fn main_() {
     //let cornell_camera = SimpleAACamera::new(PI/6., cornell_camera_pos, -cornell_camera_pos.normalise(), Vec3::Y);
     // render(&cornell_phone_scene(), &cornell_camera, 1440, 3120, 512, 4, "phone_cornell.png".to_string()); //, "test.png".to_string()
 
-    let cornell_camera_pos = Vec3::new(0., 0., -3.1);
-    let mut cornell_camera = DOFCamera::new(PI/3., cornell_camera_pos, -cornell_camera_pos.normalise(), Vec3::Y, 3.1, 0.0);
+    // The same model can be given to the GPU viewer
+    let cornell_model = CameraModel::look_at(Vec3::new(0., 0., -3.1), Vec3::ZERO, Vec3::Y, Fov::Horizontal(PI/3.), 0.0);
+    let mut cornell_camera = DOFCamera::from_model(&cornell_model, 1024, 1024);
 
     let sphere_camera_pos = Vec3::new(4., 0.6, -8.);
     let mut sphere_camera = DOFCamera::new(PI/3., sphere_camera_pos, -sphere_camera_pos.normalise(), Vec3::Y, sphere_camera_pos.norm(), 0.1);
@@ -208,7 +369,35 @@ fn main_() {
     println!("Setting up: {} milliseconds", setting_up);
 
     
-    render_window_iter(&cornell_box_scene(), &mut cornell_camera, 1024, 1024, 12); //, "test.png".to_string()
+    let (cornell_scene, cornell_lights) = cornell_box_scene();
+    let integrator = Integrator {
+        depth: Depth { min: 3, max: 12 },
+        spectral: None, // Some(Spectral::new()) for dispersion
+        seed: 0,
+        sampler: SamplerKind::Sobol,
+    };
+    render_window_iter(&cornell_scene, &cornell_lights, &mut cornell_camera, 1024, 1024, &integrator); //, "test.png".to_string()
+    //let limits = RenderLimits { time: Some(Duration::from_secs(60)), error: Some(0.01), max_samples: 4096, batch: 8 };
+    //let settings = RenderSettings { width: 512, height: 512, limits, filter: Filter::MITCHELL, integrator: integrator.clone() };
+    //render(&cornell_scene, &cornell_lights, &cornell_camera, &settings, "bounded.png".to_string());
+    //let adaptive = Adaptive { min_samples: 16, batch: 16, max_samples: 4096, threshold: 0.02, budget: 512 * 512 * 256 };
+    // Through a real 50mm lens on full frame film, focused on the back wall
+    //let elements = lens::LensCamera::load_prescription(std::path::Path::new("dgauss.50mm.dat")).unwrap();
+    //let lens_model = CameraModel::new(Vec3::new(0., 0., -3.1), Vec3::Z, Vec3::Y, Fov::Horizontal(PI/3.), 4.1, 0.);
+    //let lens_camera = lens::LensCamera::from_model(&lens_model, &lens::Lens::new(elements, 0.0433), 512, 512).unwrap();
+    //render(&cornell_scene, &cornell_lights, &lens_camera, &settings, "lens.png".to_string());
+    // A flythrough recorded in the viewer with R
+    //let path = CameraPath::load(std::path::Path::new("camera_path.txt")).unwrap();
+    //render_path(&cornell_scene, &cornell_lights, &path, Fov::Vertical(PI/2.), 24., &RenderSettings { limits: RenderLimits::samples(64), ..settings.clone() }, "flythrough").unwrap();
+    // Stereo: both eyes in one image, or an anaglyph from a camera per eye
+    //let rig = StereoRig::new(cornell_model, 0.065, 3.1);
+    //render(&cornell_scene, &cornell_lights, &rig.camera(StereoLayout::SideBySide, 1024, 512), &RenderSettings { width: 1024, ..settings.clone() }, "stereo.png".to_string());
+    //render_anaglyph(&cornell_scene, &cornell_lights, &rig.eye(Eye::Left, 512, 512), &rig.eye(Eye::Right, 512, 512), &settings, "anaglyph.png".to_string());
+    //render(&cornell_scene, &cornell_lights, &rig.ods(StereoLayout::TopBottom), &RenderSettings { width: 1024, height: 1024, ..settings.clone() }, "ods.hdr".to_string());
+    // Analytic shapes and a distance field, with a quad and a disk as lights
+    //let (shapes, shapes_lights) = shapes_scene();
+    //render(&shapes, &shapes_lights, &sphere_camera, &settings, "shapes.png".to_string());
+    //render_adaptive(&cornell_scene, &cornell_lights, &cornell_camera, &settings, &adaptive, "adaptive.png".to_string());
 
     println!("Rendering: {} seconds", (now.elapsed().as_millis() - setting_up) / 1000);
 }
@@ -234,103 +423,253 @@ fn _background(ray: Ray) -> Colour {
     sun + 0.4 * sky
 }
 
-fn trace<T: Object>(scene: &T, ray: Ray, depth: usize) -> Colour {
-    if depth <= 0 { return Colour::BLACK };
+/// Next event estimation on a Lambertian surface; the albedo and colour are applied by the caller.
+/// The ray is the one that arrived at the point; shadow rays go at the same time
+fn direct_light<T: Object>(scene: &T, lights: &LightSampler, ray: Ray, point: Vec3, normal: Vec3, path: PathState, sampler: &mut dyn Sampler) -> Colour {
+    match lights.sample(point, normal, sampler) {
+        Some(sample) => {
+            let cos = dot(sample.direction, normal);
+            if cos <= 0. { return Colour::BLACK; }
+
+            // Anything closer than the light blocks it (media included)
+            match scene.intersect(ray.spawn(point, sample.direction), sampler) {
+                Some(hit) if hit.dist < (1. - 1e-3) * sample.dist => Colour::BLACK,
+                _ => cos / (PI * sample.pdf) * path.emitted(sample.radiance),
+            }
+        },
+        None => Colour::BLACK,
+    }
+}
+
+/// How long paths get; past min they are cut off randomly (Russian roulette)
+/// depending on their throughput, and they always stop at max
+#[derive(Copy, Clone)]
+struct Depth {
+    min: usize,
+    max: usize,
+}
+
+/// What a path carries from bounce to bounce
+#[derive(Copy, Clone)]
+struct PathState {
+    bounces: usize,
+    throughput: Colour, // Product of the weights so far
+    count_emission: bool, // Not after a diffuse bounce for lights that were already sampled directly
+    wavelengths: Option<Wavelengths>, // When rendering spectrally colours hold a value per wavelength
+}
+
+impl PathState {
+    const START: PathState = PathState { bounces: 0, throughput: Colour::WHITE, count_emission: true, wavelengths: None };
+
+    /// The path after scattering with the given weight
+    fn bounce(self, weight: Colour, count_emission: bool) -> PathState {
+        PathState {
+            bounces: self.bounces + 1,
+            throughput: weight * self.throughput,
+            count_emission: count_emission,
+            ..self
+        }
+    }
+
+    /// An emitted colour in whatever the path is carrying
+    fn emitted(&self, colour: Colour) -> Colour {
+        match self.wavelengths {
+            Some(wavelengths) => wavelengths.illuminant(colour),
+            None => colour,
+        }
+    }
+
+    /// A surface colour in whatever the path is carrying
+    fn reflected(&self, colour: Colour) -> Colour {
+        match self.wavelengths {
+            Some(wavelengths) => wavelengths.reflectance(colour),
+            None => colour,
+        }
+    }
+}
+
+/// Settings for turning camera rays into colours
+#[derive(Clone)]
+struct Integrator {
+    depth: Depth,
+    spectral: Option<Spectral>, // Trace a few wavelengths per path instead of RGB
+    seed: u64, // Same seed and settings give the same image
+    sampler: SamplerKind,
+}
+
+impl Integrator {
+    /// Random numbers for one sample of a pixel
+    fn sampler(&self, pixel: usize, sample: usize) -> Box<dyn Sampler> {
+        self.sampler.new_sampler(self.seed, pixel, sample)
+    }
+
+    /// One sample of a pixel; where on the film it was taken and the colour seen there
+    fn sample_pixel<O: Object, C: Camera>(&self, scene: &O, lights: &LightSampler, camera: &C,
+                                          x: usize, y: usize, width: usize, height: usize, sample: usize) -> (Float, Float, Colour) {
+        let mut sampler = self.sampler(x + y * width, sample);
+        let film_x = x as Float + random_float(&mut *sampler);
+        let film_y = y as Float + random_float(&mut *sampler);
+
+        let ray = camera.generate_ray_at(film_x, film_y, width, height, &mut *sampler);
+        // Blocked by a lens or off a fisheye's circle; still a black sample for the film
+        if ray.direction.norm() < EPS { return (film_x, film_y, Colour::BLACK) };
+        (film_x, film_y, self.radiance(scene, lights, ray, &mut *sampler))
+    }
+
+    /// Estimate of the (RGB) colour seen along a camera ray
+    fn radiance<T: Object>(&self, scene: &T, lights: &LightSampler, ray: Ray, sampler: &mut dyn Sampler) -> Colour {
+        // Cameras give film they don't cover (outside a fisheye circle) rays with no direction
+        if ray.direction.norm() < EPS { return Colour::BLACK };
+
+        match &self.spectral {
+            Some(spectral) => {
+                let wavelengths = spectral.sample_wavelengths(random_float(sampler));
+                let path = PathState { wavelengths: Some(wavelengths), ..PathState::START };
+                wavelengths.to_rgb(trace(scene, lights, ray, self.depth, path, sampler))
+            },
+            None => trace(scene, lights, ray, self.depth, PathState::START, sampler),
+        }
+    }
+}
+
+fn trace<T: Object>(scene: &T, lights: &LightSampler, ray: Ray, depth: Depth, path: PathState, sampler: &mut dyn Sampler) -> Colour {
+    if path.bounces >= depth.max { return Colour::BLACK };
+
+    // Russian roulette; surviving paths are weighted up so it stays unbiased
+    let mut survival: Float = 1.;
+    if path.bounces >= depth.min {
+        survival = path.throughput.x.max(path.throughput.y).max(path.throughput.z).min(1.);
+        if random_float(sampler) >= survival { return Colour::BLACK };
+    }
+    let path = PathState { throughput: (1. / survival) * path.throughput, ..path };
+
+    (1. / survival) * trace_bounce(scene, lights, ray, depth, path, sampler)
+}
+
+/// One bounce of the path; trace does the bookkeeping
+fn trace_bounce<T: Object>(scene: &T, lights: &LightSampler, ray: Ray, depth: Depth, path: PathState, sampler: &mut dyn Sampler) -> Colour {
 
     //let hit = scene.intersect(ray);
 
-    match scene.intersect(ray) {
+    match scene.intersect(ray, sampler) {
         // This notation is actually a bit cumbersome; it unwraps the hit
         // whilst ignoring distance which isn't used. I'm not sure whether
         // this is fast or not
-        Some(ObjectHit{point, normal, dist: _, material, colour}) => colour * match material {
-            Material::Lambert(albedo) => {
-                let new_direction = random_hemisphere(normal);
-                if dot(new_direction, normal) <= 0. {
-                    println!("Now this is good")
+        Some(ObjectHit{point, normal, dist: _, material, colour}) => {
+            let colour = match material {
+                Material::Light(_) | Material::LightUni(_) | Material::LightCos(_) => path.emitted(colour),
+                _ => path.reflected(colour),
+            };
+
+            colour * match material {
+                Material::Lambert(albedo) => {
+                    let new_direction = random_hemisphere(normal, sampler);
+                    if dot(new_direction, normal) <= 0. {
+                        println!("Now this is good")
+                    }
+                    let weight = 2. * dot(new_direction, normal).max(0.) * albedo;
+                    weight * trace(scene, lights,
+                        ray.spawn(point + EPS * normal, new_direction), depth, path.bounce(weight * colour, true), sampler)
+                },
+
+                Material::LambertCos(albedo) => {
+
+                    if dot(ray.direction, normal) < 0. {
+                        let origin = point + EPS * normal;
+                        let new_direction = random_hemisphere_cosine(normal, sampler);
+                        albedo * (direct_light(scene, lights, ray, origin, normal, path, sampler)
+                            + trace(scene, lights, ray.spawn(origin, new_direction), depth, path.bounce(albedo * colour, lights.is_empty()), sampler))
+                    } else {
+                        Colour::BLACK
+                    }
                 }
-                2. * dot(new_direction, normal).max(0.) * albedo * trace(scene,
-                    Ray::new(point + EPS * normal, new_direction), depth - 1)
-            },
 
-            Material::LambertCos(albedo) => {
+                Material::Mirror(albedo) => {
+                    let new_direction = reflect(ray.direction, normal); // dot(new_direction, normal).max(0.) * 
+                    albedo * trace(scene, lights,
+                        ray.spawn(point + EPS * normal, new_direction), depth, path.bounce(albedo * colour, true), sampler)
+                },
 
-                if dot(ray.direction, normal) < 0. {
-                    let new_direction = random_hemisphere_cosine(normal);
-                    albedo * trace(scene, Ray::new(point + EPS * normal, new_direction), depth - 1)
-                } else {
-                    Colour::BLACK
-                }
-            }
+                // Glass with refractive index refr
+                Material::Glass(n_dielectric) => dielectric(scene, lights, ray, point, normal, n_dielectric, colour, depth, path, sampler),
 
-            Material::Mirror(albedo) => {
-                let new_direction = reflect(ray.direction, normal); // dot(new_direction, normal).max(0.) * 
-                albedo * trace(scene,
-                    Ray::new(point + EPS * normal, new_direction), depth - 1)
-            },
+                // Each wavelength refracts differently, so only the hero can carry on
+                Material::Dispersive(ior) => match path.wavelengths {
+                    Some(wavelengths) if !wavelengths.secondary_terminated => {
+                        let path = PathState { wavelengths: Some(wavelengths.terminate_secondary()), ..path };
+                        Colour::new(WAVELENGTHS as Float, 0., 0.) * dielectric(scene, lights, ray, point, normal, ior.at(wavelengths.lambda.x), colour, depth, path, sampler)
+                    },
+                    Some(wavelengths) => dielectric(scene, lights, ray, point, normal, ior.at(wavelengths.lambda.x), colour, depth, path, sampler),
+                    None => dielectric(scene, lights, ray, point, normal, ior.at(Ior::D_LINE), colour, depth, path, sampler),
+                },
 
-            // Glass with refractive index refr
-            Material::Glass(n_dielectric) => {
-                let cos = dot(ray.direction, normal);
-                let ratio: Float;
-                //let sl: Float;
-                //let refract_ray: Ray;
-
-                if cos < 0. {
-                    // Ray coming from outside
-                    ratio = 1./n_dielectric;
-                } else {
-                    // Ray coming from inside
-                    ratio = n_dielectric;
+                Material::Scatter(g) => {
+                    let new_direction = random_float(sampler).signum() * random_vector(sampler); // Not great way of sampling from whole sphere
+                    let cos = dot(new_direction, ray.direction);
+
+                    let weight = henyey_greenstein(cos, g) / (2.*PI);
+                    weight * colour * trace(scene, lights,
+                        ray.spawn(point, new_direction), depth, path.bounce(weight * colour * colour, true), sampler)
                 }
 
-                match refract(ray.direction, -cos.signum() * normal, ratio) {
-                    Some(refract_direction) => {
-                        let schlick_factor = schlick(cos.abs(), n_dielectric);
+                // Only the lights direct lighting sampled; anything else emissive still counts
+                Material::Light(_) | Material::LightUni(_) if !path.count_emission && lights.contains(point, normal) => Colour::BLACK,
 
-                        let reflect_direction = reflect(ray.direction, -cos.signum() * normal);
+                Material::Light(intensity) => intensity * Colour::WHITE, // Note lights are omnidirectional; they light in front and behind of themselves
+            
+                Material::LightUni(intensity) => intensity * dot(normal, ray.direction).is_sign_negative() as usize as Float * Colour::WHITE, // Unidirectional light
+            
+                Material::LightCos(intensity) => dot(ray.direction, -normal).max(0.).powf(100.) * intensity * Colour::WHITE, // This is unidirectional
 
-                        if random_float() < schlick_factor { //dot(reflect_direction, normal).abs() * 
-                            trace(scene,
-                                Ray::new(point + EPS * -cos.signum() * normal, reflect_direction), depth - 1)    
-                        } else {
-                            trace(scene,
-                                Ray::new(point + EPS * cos.signum() * normal, refract_direction), depth - 1)    
-                        }
+                Material::Test => Colour::WHITE,
+            }
+        },
 
-                    },
-                    None => {
-                        let new_direction = reflect(ray.direction, normal);
-                        
-                        trace(scene,
-                            Ray::new(point + EPS * -cos.signum() * normal, new_direction), depth - 1)
-                    },
-                }
+        None => path.emitted(background(ray)),
+    }
+}
 
-            }
+/// Reflection or refraction at the boundary of a dielectric
+fn dielectric<T: Object>(scene: &T, lights: &LightSampler, ray: Ray, point: Vec3, normal: Vec3, n_dielectric: Float,
+                         colour: Colour, depth: Depth, path: PathState, sampler: &mut dyn Sampler) -> Colour {
+    let cos = dot(ray.direction, normal);
+    let ratio: Float;
+    //let sl: Float;
+    //let refract_ray: Ray;
+
+    if cos < 0. {
+        // Ray coming from outside
+        ratio = 1./n_dielectric;
+    } else {
+        // Ray coming from inside
+        ratio = n_dielectric;
+    }
+
+    match refract(ray.direction, -cos.signum() * normal, ratio) {
+        Some(refract_direction) => {
+            let schlick_factor = schlick(cos.abs(), n_dielectric);
 
-            Material::Scatter(g) => {
-                let new_direction = random_float().signum() * random_vector(); // Not great way of sampling from whole sphere
-                let cos = dot(new_direction, ray.direction);
+            let reflect_direction = reflect(ray.direction, -cos.signum() * normal);
 
-                henyey_greenstein(cos, g) * colour * trace(scene,
-                    Ray::new(point, new_direction), depth - 1)/ (2.*PI)
+            if random_float(sampler) < schlick_factor { //dot(reflect_direction, normal).abs() * 
+                trace(scene, lights,
+                    ray.spawn(point + EPS * -cos.signum() * normal, reflect_direction), depth, path.bounce(colour, true), sampler)    
+            } else {
+                trace(scene, lights,
+                    ray.spawn(point + EPS * cos.signum() * normal, refract_direction), depth, path.bounce(colour, true), sampler)    
             }
 
-            Material::Light(intensity) => intensity * Colour::WHITE, // Note lights are omnidirectional; they light in front and behind of themselves
-            
-            Material::LightUni(intensity) => intensity * dot(normal, ray.direction).is_sign_negative() as usize as Float * Colour::WHITE, // Unidirectional light
+        },
+        None => {
+            let new_direction = reflect(ray.direction, normal);
             
-            Material::LightCos(intensity) => dot(ray.direction, -normal).max(0.).powf(100.) * intensity * Colour::WHITE, // This is unidirectional
-
-            Material::Test => Colour::WHITE,
+            trace(scene, lights,
+                ray.spawn(point + EPS * -cos.signum() * normal, new_direction), depth, path.bounce(colour, true), sampler)
         },
-
-        None => background(ray),
     }
 }
 
-fn cornell_box_scene() -> Vec<Box<dyn Object + Sync + Send>> {
+fn cornell_box_scene() -> (Vec<Box<dyn Object + Sync + Send>>, LightSampler) {
 
     let red = Colour::new(0.71, 0., 0.);
     let green = Colour::new(0., 0.71, 0.);
@@ -424,11 +763,13 @@ fn cornell_box_scene() -> Vec<Box<dyn Object + Sync + Send>> {
         colour: Colour::WHITE,
     };
 
-    vec![Box::new(bottom), Box::new(top), Box::new(left), Box::new(right), Box::new(back), Box::new(light), Box::new(mirror_ball), Box::new(glass_cube)]
+    let lights = LightSampler::new(emitters_from_collection(&light));
+
+    (vec![Box::new(bottom), Box::new(top), Box::new(left), Box::new(right), Box::new(back), Box::new(light), Box::new(mirror_ball), Box::new(glass_cube)], lights)
     //vec![Box::new(gas), Box::new(light2), Box::new(mirror_ball), Box::new(glass_ball)]
 }
 
-fn cornell_phone_scene() -> Vec<Box<dyn Object>> {
+fn cornell_phone_scene() -> (Vec<Box<dyn Object>>, LightSampler) {
 
     let red = Colour::new(0.71, 0., 0.);
     let green = Colour::new(0., 0.71, 0.);
@@ -499,11 +840,13 @@ fn cornell_phone_scene() -> Vec<Box<dyn Object>> {
         colour: Colour::WHITE
     };
     
-    vec![Box::new(bottom), Box::new(top), Box::new(left), Box::new(right), Box::new(back), Box::new(light), Box::new(mirror_ball), Box::new(glass_ball)]
+    let lights = LightSampler::new(emitters_from_collection(&light));
+
+    (vec![Box::new(bottom), Box::new(top), Box::new(left), Box::new(right), Box::new(back), Box::new(light), Box::new(mirror_ball), Box::new(glass_ball)], lights)
 }
 
 
-fn sphere_test_scene() -> Vec<Box<dyn Object + Sync + Send>> {
+fn sphere_test_scene() -> (Vec<Box<dyn Object + Sync + Send>>, LightSampler) {
     let left = GeneralObject::<Sphere> {
         shape: Sphere {
             centre: Vec3::X,
@@ -556,11 +899,70 @@ fn sphere_test_scene() -> Vec<Box<dyn Object + Sync + Send>> {
         colour: Colour::new(0., 173., 223.) / 255.,
     };
 
-    vec![Box::new(left), Box::new(middle2), Box::new(right), Box::new(floor), Box::new(light)]
+    let lights = LightSampler::new(emitters_from_sphere(&light));
+
+    (vec![Box::new(left), Box::new(middle2), Box::new(right), Box::new(floor), Box::new(light)], lights)
 }
 
 
-fn obj_scene() -> Vec<Box<dyn Object + Sync + Send>> {
+/// One of each analytic shape and a distance field on a floor, lit by a quad overhead and a disk to the side
+fn shapes_scene() -> (Vec<Box<dyn Object + Sync + Send>>, LightSampler) {
+    let cylinder = GeneralObject::<Cylinder> {
+        shape: Cylinder::new(Vec3::new(-1.6, -1., 0.), Vec3::Y, 0.5, 1.2),
+        material: Material::LambertCos(0.8),
+        colour: Colour::new(0.8, 0.3, 0.3),
+    };
+
+    let cone = GeneralObject::<Cone> {
+        shape: Cone::new(Vec3::new(0., -1., 0.5), Vec3::Y, 0.6, 1.4),
+        material: Material::LambertCos(0.8),
+        colour: Colour::new(0.3, 0.7, 0.35),
+    };
+
+    let cube = GeneralObject::<AABox> {
+        shape: AABox::new(Vec3::new(1., -1., -0.4), Vec3::new(2., 0., 0.6)),
+        material: Material::Glass(1.54),
+        colour: Colour::WHITE,
+    };
+
+    // A rounded box with a bowl scooped out of the top and a ring round the rim
+    let rim = Vec3::new(-0.4, -0.3, -1.);
+    let dish = GeneralObject::<SdfShape> {
+        shape: SdfShape::new(Sdf::RoundBox { centre: Vec3::new(-0.4, -0.65, -1.), half: Vec3::new(0.35, 0.35, 0.35), radius: 0.08 }
+            .smooth_subtraction(Sdf::Sphere { centre: rim, radius: 0.3 }, 0.05)
+            .smooth_union(Sdf::Torus { centre: rim, major: 0.3, minor: 0.07 }, 0.05)),
+        material: Material::LambertCos(0.8),
+        colour: Colour::new(0.35, 0.45, 0.8),
+    };
+
+    let floor = GeneralObject::<Plane> {
+        shape: Plane::new(Vec3::Y, -Vec3::Y),
+        material: Material::LambertCos(0.9),
+        colour: Colour::new(0.3, 0.25, 0.25),
+    };
+
+    // Facing down, so the edges go x then z
+    let panel = GeneralObject::<Quad> {
+        shape: Quad::new(Vec3::new(-1., 2.5, -1.), 2. * Vec3::X, 2. * Vec3::Z),
+        material: Material::LightUni(4.),
+        colour: Colour::WHITE,
+    };
+
+    let lamp = GeneralObject::<Disk> {
+        shape: Disk::new(Vec3::new(-3.5, 0.5, -1.), Vec3::new(1., -0.2, 0.3), 0.4),
+        material: Material::LightUni(8.),
+        colour: Colour::new(1., 0.776, 0.4),
+    };
+
+    let mut emitters = emitters_from_surface(&panel);
+    emitters.extend(emitters_from_surface(&lamp));
+    let lights = LightSampler::new(emitters);
+
+    (vec![Box::new(cylinder), Box::new(cone), Box::new(cube), Box::new(dish), Box::new(floor), Box::new(panel), Box::new(lamp)], lights)
+}
+
+
+fn obj_scene() -> (Vec<Box<dyn Object + Sync + Send>>, LightSampler) {
     /* OBJ Mesh */
 
     
@@ -568,7 +970,7 @@ fn obj_scene() -> Vec<Box<dyn Object + Sync + Send>> {
     let obj_mesh: obj::Obj<obj::SimplePolygon> = obj::Obj::load(obj_path).expect("Failed to load obj file");
     
     let obj = ObjectCollection::<Triangle> {
-        shapes: convert_objects_to_polygons(&obj_mesh),
+        shapes: convert_objects_to_polygons(&obj_mesh, PI / 3.),
         //material: Material::Glass(1.54),
         material: Material::LambertCos(0.60),
         colour: Colour::new(1., 1., 1.),
@@ -580,5 +982,57 @@ fn obj_scene() -> Vec<Box<dyn Object + Sync + Send>> {
         colour: Colour::new(0.3, 0.25, 0.25),
     };
 
-    vec![Box::new(obj), Box::new(floor)]
+    // Any emissive triangles in the mesh become lights; the tree helps when there are many.
+    // Only the first shuttle's are sampled; the copy's are found by bouncing into them
+    let lights = LightSampler::with_tree(emitters_from_collection(&obj));
+
+    // A second, smaller shuttle turned round, sharing the first one's triangles
+    let mesh = Arc::new(obj);
+    let copy = Instance::new(mesh.clone(), transform::Matrix4::translation(Vec3::new(4., 0., -3.))
+        .mul(&transform::Matrix4::rotation(Vec3::Y, PI / 4.))
+        .mul(&transform::Matrix4::scale(Vec3::new(0.5, 0.5, 0.5)))).unwrap();
+    let original = Instance::new(mesh, transform::Matrix4::IDENTITY).unwrap();
+
+    (vec![Box::new(original), Box::new(copy), Box::new(floor)], lights)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 4] = [SamplerKind::Independent, SamplerKind::Stratified(4), SamplerKind::Halton, SamplerKind::Sobol];
+
+    #[test]
+    fn sample_pixel_is_repeatable() {
+        let (scene, lights) = cornell_box_scene();
+        let model = CameraModel::look_at(Vec3::new(0., 0., -3.1), Vec3::ZERO, Vec3::Y, Fov::Horizontal(PI/3.), 0.0);
+        let camera = DOFCamera::from_model(&model, 32, 32);
+
+        for kind in KINDS.iter() {
+            let integrator = Integrator { depth: Depth { min: 3, max: 12 }, spectral: None, seed: 7, sampler: *kind };
+            for &(x, y, sample) in [(3, 5, 0), (16, 16, 3), (31, 0, 17)].iter() {
+                let (x0, y0, a) = integrator.sample_pixel(&scene, &lights, &camera, x, y, 32, 32, sample);
+                let (x1, y1, b) = integrator.sample_pixel(&scene, &lights, &camera, x, y, 32, 32, sample);
+                assert_eq!((x0, y0), (x1, y1));
+                assert_eq!((a.x, a.y, a.z), (b.x, b.y, b.z));
+            }
+        }
+    }
+
+    /// Every pixel's first few dimensions, worked out on a pool with this many threads
+    fn streams(kind: SamplerKind, threads: usize) -> Vec<Vec<Float>> {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        pool.install(|| (0..256usize).into_par_iter().map(|pixel| {
+            let mut sampler = kind.new_sampler(7, pixel, pixel % 5);
+            (0..8).map(|_| sampler.next_float()).collect()
+        }).collect())
+    }
+
+    #[test]
+    fn samplers_ignore_thread_count() {
+        for kind in KINDS.iter() {
+            assert_eq!(streams(*kind, 1), streams(*kind, 4));
+        }
+    }
 }
@@ -134,6 +134,28 @@ impl Uniform {
         }
     }
 
+    pub fn push_2f(&self, x: f32, y: f32) {
+        unsafe {
+            gl::ProgramUniform2f(
+                self.shader_id,
+                self.handle,
+                x,
+                y
+            )
+        }
+    }
+
+    pub fn push_2i(&self, x: i32, y: i32) {
+        unsafe {
+            gl::ProgramUniform2i(
+                self.shader_id,
+                self.handle,
+                x,
+                y
+            )
+        }
+    }
+
     pub fn push_1ui(&self, i: u32) {
         unsafe {
             gl::ProgramUniform1ui(
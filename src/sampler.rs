@@ -0,0 +1,294 @@
+/* Random numbers for the CPU tracer; every pixel sample gets its own stream from a
+   global seed, so renders come out the same whatever the threads get up to.
+   A sampler hands out the dimensions of one sample in order: pixel jitter, lens, then
+   whatever each bounce asks for */
+#![allow(dead_code)]
+use super::vector::*;
+
+pub trait Sampler {
+    /// The next dimension of this sample, uniform in [0, 1)
+    fn next_float(&mut self) -> Float;
+}
+
+/// Which sampler to make for each pixel sample
+#[derive(Copy, Clone)]
+pub enum SamplerKind {
+    Independent,
+    Stratified(usize), // Number of strata in each dimension; best with a multiple of it as the sample count
+    Halton,
+    Sobol,
+}
+
+impl SamplerKind {
+    pub fn new_sampler(&self, seed: u64, pixel: usize, sample: usize) -> Box<dyn Sampler> {
+        match *self {
+            SamplerKind::Independent => Box::new(Independent::new(seed, pixel, sample)),
+            SamplerKind::Stratified(strata) => Box::new(Stratified::new(strata, seed, pixel, sample)),
+            SamplerKind::Halton => Box::new(Halton::new(seed, pixel, sample)),
+            SamplerKind::Sobol => Box::new(Sobol::new(seed, pixel, sample)),
+        }
+    }
+}
+
+/// SplitMix64 finaliser; scrambles seeds so nearby pixels get unrelated streams
+fn splitmix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Scramble for one pixel, shared by all of its samples
+fn pixel_hash(seed: u64, pixel: usize) -> u64 {
+    splitmix(splitmix(seed) ^ pixel as u64)
+}
+
+/// Top 24 bits of a u32 as a float in [0, 1)
+fn to_float(x: u32) -> Float {
+    (x >> 8) as Float / (1u32 << 24) as Float
+}
+
+/// A PCG32 generator (O'Neill's pcg32_random_r); plain white noise
+pub struct Independent {
+    state: u64,
+}
+
+impl Independent {
+    const MULTIPLIER: u64 = 6364136223846793005;
+    const INCREMENT: u64 = 1442695040888963407;
+
+    /// The stream for one sample of one pixel
+    pub fn new(seed: u64, pixel: usize, sample: usize) -> Independent {
+        Independent::from_hash(splitmix(pixel_hash(seed, pixel) ^ sample as u64))
+    }
+
+    fn from_hash(state: u64) -> Independent {
+        let mut sampler = Independent { state };
+        sampler.next_u32();
+        sampler
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(Independent::MULTIPLIER).wrapping_add(Independent::INCREMENT);
+
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+}
+
+impl Sampler for Independent {
+    /// Only 24 bits so it never rounds up to 1
+    fn next_float(&mut self) -> Float {
+        to_float(self.next_u32())
+    }
+}
+
+/// Kensler's hashed permutation of 0..len; a different shuffle for each p
+fn permute(mut i: u32, len: u32, p: u32) -> u32 {
+    let mut w = len - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        // Cycle walk until it lands inside the range
+        if i < len { break; }
+    }
+
+    (i.wrapping_add(p)) % len
+}
+
+/// Jittered strata in every dimension, shuffled separately per dimension so the
+/// dimensions don't line up with each other
+pub struct Stratified {
+    strata: u32,
+    sample: u32,
+    pixel: u64,
+    dimension: u64,
+    jitter: Independent,
+}
+
+impl Stratified {
+    pub fn new(strata: usize, seed: u64, pixel: usize, sample: usize) -> Stratified {
+        Stratified {
+            strata: strata.max(1) as u32,
+            sample: sample as u32,
+            pixel: pixel_hash(seed, pixel),
+            dimension: 0,
+            jitter: Independent::new(seed, pixel, sample),
+        }
+    }
+}
+
+impl Sampler for Stratified {
+    fn next_float(&mut self) -> Float {
+        // Every pass through the strata gets a new shuffle
+        let pass = (self.sample / self.strata) as u64;
+        let shuffle = splitmix(self.pixel ^ splitmix(self.dimension ^ (pass << 32))) as u32;
+        let stratum = permute(self.sample % self.strata, self.strata, shuffle);
+        self.dimension += 1;
+
+        ((stratum as Float + self.jitter.next_float()) / self.strata as Float).min(1. - EPS)
+    }
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+];
+
+/// The digits of i in the given base, mirrored around the decimal point
+pub fn radical_inverse(base: u32, mut i: u32) -> Float {
+    let inverse_base = 1. / base as f64;
+    let mut reversed = 0.;
+    let mut scale = inverse_base;
+
+    while i > 0 {
+        reversed += (i % base) as f64 * scale;
+        i /= base;
+        scale *= inverse_base;
+    }
+
+    reversed as Float
+}
+
+/// The Halton sequence, one prime per dimension, with a random toroidal shift per pixel so
+/// neighbours don't show the same pattern. Past the prime table it is just white noise
+pub struct Halton {
+    index: u32,
+    pixel: u64,
+    dimension: usize,
+    fallback: Independent,
+}
+
+impl Halton {
+    pub fn new(seed: u64, pixel: usize, sample: usize) -> Halton {
+        Halton {
+            index: sample as u32,
+            pixel: pixel_hash(seed, pixel),
+            dimension: 0,
+            fallback: Independent::new(seed, pixel, sample),
+        }
+    }
+}
+
+impl Sampler for Halton {
+    fn next_float(&mut self) -> Float {
+        let dimension = self.dimension;
+        self.dimension += 1;
+
+        if dimension >= PRIMES.len() {
+            return self.fallback.next_float();
+        }
+
+        let shift = to_float(Independent::from_hash(splitmix(self.pixel ^ dimension as u64)).next_u32());
+        let x = radical_inverse(PRIMES[dimension], self.index) + shift;
+        if x >= 1. { (x - 1.).min(1. - EPS) } else { x }
+    }
+}
+
+/// Direction numbers for the first four Sobol dimensions (Joe and Kuo), one row per
+/// dimension. pt.comp has the same table as SOBOL_DIRECTIONS
+pub const SOBOL_DIRECTIONS: [[u32; 32]; 4] = [
+    [
+        0x80000000, 0x40000000, 0x20000000, 0x10000000, 0x08000000, 0x04000000, 0x02000000, 0x01000000,
+        0x00800000, 0x00400000, 0x00200000, 0x00100000, 0x00080000, 0x00040000, 0x00020000, 0x00010000,
+        0x00008000, 0x00004000, 0x00002000, 0x00001000, 0x00000800, 0x00000400, 0x00000200, 0x00000100,
+        0x00000080, 0x00000040, 0x00000020, 0x00000010, 0x00000008, 0x00000004, 0x00000002, 0x00000001,
+    ],
+    [
+        0x80000000, 0xc0000000, 0xa0000000, 0xf0000000, 0x88000000, 0xcc000000, 0xaa000000, 0xff000000,
+        0x80800000, 0xc0c00000, 0xa0a00000, 0xf0f00000, 0x88880000, 0xcccc0000, 0xaaaa0000, 0xffff0000,
+        0x80008000, 0xc000c000, 0xa000a000, 0xf000f000, 0x88008800, 0xcc00cc00, 0xaa00aa00, 0xff00ff00,
+        0x80808080, 0xc0c0c0c0, 0xa0a0a0a0, 0xf0f0f0f0, 0x88888888, 0xcccccccc, 0xaaaaaaaa, 0xffffffff,
+    ],
+    [
+        0x80000000, 0xc0000000, 0x60000000, 0x90000000, 0xe8000000, 0x5c000000, 0x8e000000, 0xc5000000,
+        0x68800000, 0x9cc00000, 0xee600000, 0x55900000, 0x80680000, 0xc09c0000, 0x60ee0000, 0x90550000,
+        0xe8808000, 0x5cc0c000, 0x8e606000, 0xc5909000, 0x6868e800, 0x9c9c5c00, 0xeeee8e00, 0x5555c500,
+        0x8000e880, 0xc0005cc0, 0x60008e60, 0x9000c590, 0xe8006868, 0x5c009c9c, 0x8e00eeee, 0xc5005555,
+    ],
+    [
+        0x80000000, 0xc0000000, 0x20000000, 0x50000000, 0xf8000000, 0x74000000, 0xa2000000, 0x93000000,
+        0xd8800000, 0x25400000, 0x59e00000, 0xe6d00000, 0x78080000, 0xb40c0000, 0x82020000, 0xc3050000,
+        0x208f8000, 0x51474000, 0xfbea2000, 0x75d93000, 0xa0858800, 0x914e5400, 0xdbe79e00, 0x25db6d00,
+        0x58800080, 0xe54000c0, 0x79e00020, 0xb6d00050, 0x800800f8, 0xc00c0074, 0x200200a2, 0x50050093,
+    ],
+];
+
+/// One dimension of the unscrambled Sobol point with the given index
+fn sobol(index: u32, dimension: usize) -> u32 {
+    let mut x = 0;
+    for (bit, direction) in SOBOL_DIRECTIONS[dimension].iter().enumerate() {
+        if (index >> bit) & 1 == 1 {
+            x ^= direction;
+        }
+    }
+    x
+}
+
+/// Laine-Karras style hash that only lets bits affect the ones above them
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+/// Owen scrambling by hashing the reversed bits (Burley 2020)
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+/// Owen-scrambled Sobol. Only four dimensions have direction numbers, so the dimensions go
+/// in groups of four, each with its own scramble and shuffle of the sample order
+pub struct Sobol {
+    index: u32,
+    pixel: u64,
+    dimension: usize,
+}
+
+impl Sobol {
+    pub fn new(seed: u64, pixel: usize, sample: usize) -> Sobol {
+        Sobol {
+            index: sample as u32,
+            pixel: pixel_hash(seed, pixel),
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for Sobol {
+    fn next_float(&mut self) -> Float {
+        let (group, component) = (self.dimension / 4, self.dimension % 4);
+        self.dimension += 1;
+
+        let seed = splitmix(self.pixel ^ group as u64) as u32;
+        let index = nested_uniform_scramble(self.index, seed);
+        let x = sobol(index, component);
+        to_float(nested_uniform_scramble(x, splitmix(seed as u64 ^ component as u64) as u32))
+    }
+}
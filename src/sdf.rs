@@ -0,0 +1,173 @@
+/* Shapes given by signed distance functions, as in shaders/old/torus.frag, built up from a few
+   primitives with smooth unions and subtractions. They are found by sphere tracing, only inside a
+   box round the whole thing, so they can sit in a scene with triangles and anything else.
+   Distances are negative inside */
+#![allow(dead_code)]
+use super::vector::*;
+use super::shape::{Shape, Hit};
+
+/// Sphere tracing stops when this close to the surface
+const THRESHOLD: Float = 1e-4;
+/// Rays that graze the surface can take forever; give up after this many steps (SDF_STEPS in pt.comp)
+const MAX_STEPS: usize = 256;
+
+/// A distance function; the primitives and ways to put them together
+pub enum Sdf {
+    Sphere { centre: Vec3, radius: Float },
+    Torus { centre: Vec3, major: Float, minor: Float }, // Lying flat, round the Y axis
+    Box { centre: Vec3, half: Vec3 },
+    RoundBox { centre: Vec3, half: Vec3, radius: Float }, // Half is to the outside of the rounding
+    SmoothUnion(Box<Sdf>, Box<Sdf>, Float), // Blended over about k
+    SmoothSubtraction(Box<Sdf>, Box<Sdf>, Float), // The first with the second cut out of it
+}
+
+fn abs(v: Vec3) -> Vec3 {
+    Vec3::new(v.x.abs(), v.y.abs(), v.z.abs())
+}
+
+fn max0(v: Vec3) -> Vec3 {
+    Vec3::new(v.x.max(0.), v.y.max(0.), v.z.max(0.))
+}
+
+fn box_distance(p: Vec3, half: Vec3) -> Float {
+    let q = abs(p) - half;
+    max0(q).norm() + q.x.max(q.y.max(q.z)).min(0.)
+}
+
+/// Inigo Quilez's polynomial smooth min; the two blend where they are within k of each other
+fn smooth_min(a: Float, b: Float, k: Float) -> Float {
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0., 1.);
+    b + h * (a - b) - k * h * (1. - h)
+}
+
+impl Sdf {
+    pub fn smooth_union(self, other: Sdf, k: Float) -> Sdf {
+        Sdf::SmoothUnion(Box::new(self), Box::new(other), k)
+    }
+
+    pub fn smooth_subtraction(self, other: Sdf, k: Float) -> Sdf {
+        Sdf::SmoothSubtraction(Box::new(self), Box::new(other), k)
+    }
+
+    pub fn distance(&self, p: Vec3) -> Float {
+        match self {
+            Sdf::Sphere { centre, radius } => (p - *centre).norm() - radius,
+            Sdf::Torus { centre, major, minor } => {
+                let p = p - *centre;
+                let ring = (p.x * p.x + p.z * p.z).sqrt() - major;
+                (ring * ring + p.y * p.y).sqrt() - minor
+            },
+            Sdf::Box { centre, half } => box_distance(p - *centre, *half),
+            Sdf::RoundBox { centre, half, radius } => {
+                box_distance(p - *centre, *half - Vec3::new(*radius, *radius, *radius)) - radius
+            },
+            Sdf::SmoothUnion(a, b, k) => smooth_min(a.distance(p), b.distance(p), *k),
+            // The smooth max of a and -b
+            Sdf::SmoothSubtraction(a, b, k) => -smooth_min(-a.distance(p), b.distance(p), *k),
+        }
+    }
+
+    /// A box everything is inside; the smooth operations can bulge out by up to k/4
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        match self {
+            Sdf::Sphere { centre, radius } => {
+                let r = Vec3::new(*radius, *radius, *radius);
+                (*centre - r, *centre + r)
+            },
+            Sdf::Torus { centre, major, minor } => {
+                let r = Vec3::new(major + minor, *minor, major + minor);
+                (*centre - r, *centre + r)
+            },
+            Sdf::Box { centre, half } | Sdf::RoundBox { centre, half, .. } => (*centre - *half, *centre + *half),
+            Sdf::SmoothUnion(a, b, k) => {
+                let ((lo0, hi0), (lo1, hi1)) = (a.bounds(), b.bounds());
+                let grow = Vec3::new(k / 4., k / 4., k / 4.);
+                (Vec3::new(lo0.x.min(lo1.x), lo0.y.min(lo1.y), lo0.z.min(lo1.z)) - grow,
+                 Vec3::new(hi0.x.max(hi1.x), hi0.y.max(hi1.y), hi0.z.max(hi1.z)) + grow)
+            },
+            Sdf::SmoothSubtraction(a, _, k) => {
+                let (lo, hi) = a.bounds();
+                let grow = Vec3::new(k / 4., k / 4., k / 4.);
+                (lo - grow, hi + grow)
+            },
+        }
+    }
+
+    /// Outwards; the gradient by central differences
+    pub fn normal(&self, p: Vec3) -> Vec3 {
+        let h = THRESHOLD;
+        Vec3::new(self.distance(p + h * Vec3::X) - self.distance(p - h * Vec3::X),
+                  self.distance(p + h * Vec3::Y) - self.distance(p - h * Vec3::Y),
+                  self.distance(p + h * Vec3::Z) - self.distance(p - h * Vec3::Z)).normalise()
+    }
+}
+
+/// A distance function as a Shape; hits have no uv
+pub struct SdfShape {
+    pub sdf: Sdf,
+    lo: Vec3,
+    hi: Vec3,
+}
+
+impl SdfShape {
+    pub fn new(sdf: Sdf) -> SdfShape {
+        let (lo, hi) = sdf.bounds();
+        SdfShape { sdf, lo, hi }
+    }
+
+    /// Where the ray is inside the box, if anywhere ahead
+    fn clip(&self, ray: Ray) -> Option<(Float, Float)> {
+        let (mut enter, mut leave) = (0., Float::INFINITY);
+        for (o, d, lo, hi) in [(ray.origin.x, ray.direction.x, self.lo.x, self.hi.x),
+                               (ray.origin.y, ray.direction.y, self.lo.y, self.hi.y),
+                               (ray.origin.z, ray.direction.z, self.lo.z, self.hi.z)].iter() {
+            if d.abs() < EPS * EPS {
+                if o < lo || o > hi { return None; }
+                continue;
+            }
+            let (t0, t1) = ((lo - o) / d, (hi - o) / d);
+            enter = t0.min(t1).max(enter);
+            leave = t0.max(t1).min(leave);
+        }
+        if enter <= leave { Some((enter, leave)) } else { None }
+    }
+}
+
+impl Shape for SdfShape {
+    /// Sphere tracing from wherever the ray enters the box. Rays from inside the shape march the
+    /// negated distance so they find the surface on the way out. A ray starting on the surface
+    /// (bouncing off it) has to get clear of it before anything counts as a hit
+    fn intersect(&self, ray: Ray) -> Option<Hit> {
+        let (enter, leave) = self.clip(ray)?;
+        let length = ray.direction.norm();
+
+        // Starting outside the box the ray can't be on the surface, or inside the shape.
+        // On the surface the distance's sign can't be trusted (refracted rays start only EPS
+        // inside), so the side is whichever one the ray is heading into
+        let mut t = enter;
+        let in_box = enter <= 0.;
+        let side = if !in_box {
+            1.
+        } else {
+            let d = self.sdf.distance(ray.origin);
+            if d.abs() < THRESHOLD { dot(ray.direction, self.sdf.normal(ray.origin)).signum() } else { d.signum() }
+        };
+        let mut clear = !in_box;
+
+        for _ in 0..MAX_STEPS {
+            if t > leave { return None; }
+
+            let point = ray.eval(t);
+            let d = side * self.sdf.distance(point);
+            if d < THRESHOLD {
+                if clear && t > EPS {
+                    return Some(Hit { dist: t, point, norm: self.sdf.normal(point), uv: (0., 0.) });
+                }
+            } else {
+                clear = true;
+            }
+            t += d.max(THRESHOLD) / length;
+        }
+        None
+    }
+}
@@ -7,6 +7,7 @@ pub struct Hit {
     pub dist: Float,
     pub point: Vec3, // Where the hit was
     pub norm: Vec3, //Normal at that point
+    pub uv: (Float, Float), // Barycentric for triangles (how much of b and c), texture coordinates otherwise
 }
 
 /// A primitive, all it requires is an intersection function
@@ -23,6 +24,7 @@ pub struct Triangle {
     pub b: Vec3,
     pub c: Vec3,
     pub normal: Vec3,
+    pub normals: Option<[Vec3; 3]>, // At a, b and c for smooth shading; flat if there are none
     // In/out/invert normal?
 }
 
@@ -31,37 +33,83 @@ impl Triangle {
         Triangle {
             a, b, c,
             normal: cross(b-a, c-a).normalise(),
+            normals: None,
+        }
+    }
+
+    pub fn with_normals(a: Vec3, b: Vec3, c: Vec3, normals: [Vec3; 3]) -> Triangle {
+        Triangle {
+            normals: Some(normals),
+            ..Triangle::new(a, b, c)
+        }
+    }
+
+    /// The vertex normals blended at a point; turned to the same side as the real normal, and
+    /// the real one instead where the ray sees them from different sides, since shading with
+    /// it would let light through the surface
+    fn shading_normal(&self, u: Float, v: Float, direction: Vec3) -> Vec3 {
+        let [na, nb, nc] = match self.normals {
+            Some(normals) => normals,
+            None => return self.normal,
+        };
+
+        let blended = (1. - u - v) * na + u * nb + v * nc;
+        if blended.norm() < EPS { return self.normal; }
+        let shading = dot(blended, self.normal).signum() * blended.normalise();
+
+        if dot(shading, direction) * dot(self.normal, direction) <= 0. {
+            self.normal
+        } else {
+            shading
         }
     }
 }
 
+/// Whether an edge points the positive way, going by x, then y, then z
+fn forwards(edge: Vec3) -> bool {
+    if edge.x != 0. { edge.x > 0. } else if edge.y != 0. { edge.y > 0. } else { edge.z > 0. }
+}
+
 impl Shape for Triangle {
+    /// Möller-Trumbore: the distance and barycentrics come out of one solve. Hits exactly on an
+    /// edge only count for one of the triangles sharing it, but it isn't watertight; rounding can
+    /// still let the odd ray through a shared edge or hit both sides of it
     fn intersect(&self, ray: Ray) -> Option<Hit> {
-        let norm_dot_d = dot(ray.direction, self.normal);
-        let norm_dot_o = dot(ray.origin, self.normal);
+        let edge1 = self.b - self.a;
+        let edge2 = self.c - self.a;
 
-        if norm_dot_d.abs() > EPS {
-            let t = (dot(self.a, self.normal)-norm_dot_o)/norm_dot_d;
+        let p = cross(ray.direction, edge2);
+        let det = dot(edge1, p);
+        // Ray parallel to the triangle; either side can be hit otherwise
+        if det.abs() < EPS * EPS { return None; }
+        let inv_det = 1. / det;
 
-            //let point = ray.origin + t*ray.direction;
-            let point = ray.eval(t);
+        let s = ray.origin - self.a;
+        let u = dot(s, p) * inv_det;
+        if u < 0. || u > 1. { return None; }
 
-            if t > EPS
-                && dot(self.normal, cross(point-self.a, self.b-self.a)) <= 10. * EPS
-                && dot(self.normal, cross(point-self.b, self.c-self.b)) <= 10. * EPS
-                && dot(self.normal, cross(point-self.c, self.a-self.c)) <= 10. * EPS {
-                Some(Hit {
-                    dist: t,
-                    point: point,
-                    norm: self.normal})
-            } else {
-                None
-            }
+        let q = cross(s, edge1);
+        let v = dot(ray.direction, q) * inv_det;
+        if v < 0. || u + v > 1. { return None; }
 
-        } else {
-            // Ray from wrong direction
-            None
-        }
+        // Neighbours wound the same way go along their shared edge in opposite directions, so only
+        // the one going forwards keeps it. Corners are left to every triangle
+        let edge = match (u == 0., v == 0., u + v == 1.) {
+            (true, false, false) => Some(self.a - self.c),
+            (false, true, false) => Some(self.b - self.a),
+            (false, false, true) => Some(self.c - self.b),
+            _ => None,
+        };
+        if edge.is_some_and(|edge| !forwards(edge)) { return None; }
+
+        let t = dot(edge2, q) * inv_det;
+        if t <= EPS { return None; }
+
+        Some(Hit {
+            dist: t,
+            point: ray.eval(t),
+            norm: self.shading_normal(u, v, ray.direction),
+            uv: (u, v)})
     }
 }
 
@@ -75,6 +123,11 @@ impl Sphere {
     fn normal(&self, point: Vec3) -> Vec3 {
         (point - self.centre)/self.radius
     }
+
+    /// Longitude round Y and latitude from the top, both from 0 to 1; pt.comp does the same
+    fn uv(normal: Vec3) -> (Float, Float) {
+        (0.5 + normal.z.atan2(normal.x) / (2. * PI), normal.y.clamp(-1., 1.).acos() / PI)
+    }
 }
 
 impl Shape for Sphere {
@@ -100,7 +153,8 @@ impl Shape for Sphere {
 
         let hitpos = ray.eval(res);
 
-        Some(Hit{dist: res, point: hitpos, norm: self.normal(hitpos)})
+        let norm = self.normal(hitpos);
+        Some(Hit{dist: res, point: hitpos, norm: norm, uv: Sphere::uv(norm)})
     }
 }
 
@@ -127,7 +181,7 @@ impl Shape for Plane {
         if t.abs() > 0. {
             let dist = (self.size - dot(self.normal, ray.origin))/t;
             if dist > 0. {
-                Some(Hit{dist: dist, point: ray.eval(dist), norm: self.normal})
+                Some(Hit{dist: dist, point: ray.eval(dist), norm: self.normal, uv: (0., 0.)})
             } else {
                 // Plane behind camera
                 None
@@ -137,4 +191,451 @@ impl Shape for Plane {
             None
         }
     }
-}
\ No newline at end of file
+}
+
+/*************************** ANALYTIC SHAPES ***************************/
+
+
+/// A finite shape that can be a light: its area, uniform samples over it and a box round it
+pub trait Surface: Shape {
+    fn area(&self) -> Float;
+    /// A point uniformly by area and the (outward) normal there
+    fn sample(&self, u1: Float, u2: Float) -> (Vec3, Vec3);
+    fn bounds(&self) -> (Vec3, Vec3);
+    /// The one normal of a flat shape; only these can be lit from both sides
+    fn flat(&self) -> Option<Vec3> { None }
+}
+
+/// The closer of two hits
+fn nearer(a: Option<Hit>, b: Option<Hit>) -> Option<Hit> {
+    match (a, b) {
+        (Some(a), Some(b)) => if b.dist < a.dist { Some(b) } else { Some(a) },
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+/// Where the ray crosses the plane through point with this normal, if ahead of it
+fn plane_dist(ray: Ray, point: Vec3, normal: Vec3) -> Option<Float> {
+    let t = dot(ray.direction, normal);
+    if t.abs() < EPS * EPS { return None; }
+    let dist = dot(point - ray.origin, normal) / t;
+    if dist > EPS { Some(dist) } else { None }
+}
+
+/// Both roots of a t^2 + b t + c, smallest first
+fn quadratic(a: Float, b: Float, c: Float) -> Option<(Float, Float)> {
+    if a.abs() < EPS * EPS {
+        if b.abs() < EPS * EPS { return None; }
+        return Some((-c / b, -c / b));
+    }
+    let d2 = b*b - 4.*a*c;
+    if d2 < 0. { return None; }
+    // Avoids cancellation when b is much bigger than the root
+    let q = -0.5 * (b + b.signum() * d2.sqrt());
+    let (t0, t1) = if q == 0. { (0., 0.) } else { (q / a, c / q) };
+    Some((t0.min(t1), t0.max(t1)))
+}
+
+/// Angle round an axis from 0 to 1, measured from the first vector of its onb
+fn turn(offset: Vec3, axis: Vec3) -> Float {
+    let (s, t) = onb(axis);
+    0.5 + dot(offset, t).atan2(dot(offset, s)) / (2. * PI)
+}
+
+/// A point on the unit disk, for u1 and u2 uniform; the tangents span the disk
+fn disk_point(u1: Float, u2: Float, axis: Vec3) -> Vec3 {
+    let (s, t) = onb(axis);
+    let r = u1.sqrt();
+    let phi = 2. * PI * u2;
+    r * phi.cos() * s + r * phi.sin() * t
+}
+
+/// The box round two boxes
+fn union((lo0, hi0): (Vec3, Vec3), (lo1, hi1): (Vec3, Vec3)) -> (Vec3, Vec3) {
+    (Vec3::new(lo0.x.min(lo1.x), lo0.y.min(lo1.y), lo0.z.min(lo1.z)), Vec3::new(hi0.x.max(hi1.x), hi0.y.max(hi1.y), hi0.z.max(hi1.z)))
+}
+
+/// Splits u at the boundaries in the cumulative weights, returning which part and u rescaled to it
+fn pick(u: Float, weights: &[Float]) -> (usize, Float) {
+    let total: Float = weights.iter().sum();
+    let mut u = u * total;
+    for (i, &w) in weights.iter().enumerate() {
+        if u < w || i == weights.len() - 1 {
+            return (i, (u / w).max(0.).min(1. - EPS));
+        }
+        u -= w;
+    }
+    (0, 0.)
+}
+
+
+/// A flat round disk facing along its normal; uv is the angle round and the distance from the
+/// centre over the radius
+#[derive(Copy, Clone)]
+pub struct Disk {
+    pub centre: Vec3,
+    pub normal: Vec3,
+    pub radius: Float,
+}
+
+impl Disk {
+    pub fn new(centre: Vec3, normal: Vec3, radius: Float) -> Disk {
+        Disk { centre, normal: normal.normalise(), radius }
+    }
+}
+
+impl Shape for Disk {
+    fn intersect(&self, ray: Ray) -> Option<Hit> {
+        let dist = plane_dist(ray, self.centre, self.normal)?;
+        let point = ray.eval(dist);
+        let offset = point - self.centre;
+        let r = offset.norm();
+        if r > self.radius { return None; }
+
+        Some(Hit { dist, point, norm: self.normal, uv: (turn(offset, self.normal), r / self.radius) })
+    }
+}
+
+impl Surface for Disk {
+    fn area(&self) -> Float {
+        PI * self.radius * self.radius
+    }
+
+    fn sample(&self, u1: Float, u2: Float) -> (Vec3, Vec3) {
+        (self.centre + self.radius * disk_point(u1, u2, self.normal), self.normal)
+    }
+
+    fn bounds(&self) -> (Vec3, Vec3) {
+        // How far the rim reaches along each axis
+        let n = self.normal;
+        let reach = |c: Float| self.radius * (1. - c * c).max(0.).sqrt();
+        let r = Vec3::new(reach(n.x), reach(n.y), reach(n.z));
+        (self.centre - r, self.centre + r)
+    }
+
+    fn flat(&self) -> Option<Vec3> { Some(self.normal) }
+}
+
+
+/// A cylinder with caps, from base along the axis for height; uv on the side is the angle round
+/// and the fraction of the height, on the caps as for Disk
+#[derive(Copy, Clone)]
+pub struct Cylinder {
+    pub base: Vec3, // Centre of the bottom cap
+    pub axis: Vec3,
+    pub radius: Float,
+    pub height: Float,
+}
+
+impl Cylinder {
+    pub fn new(base: Vec3, axis: Vec3, radius: Float, height: Float) -> Cylinder {
+        Cylinder { base, axis: axis.normalise(), radius, height }
+    }
+
+    fn caps(&self) -> [Disk; 2] {
+        [Disk::new(self.base, -self.axis, self.radius), Disk::new(self.base + self.height * self.axis, self.axis, self.radius)]
+    }
+
+    fn side(&self, ray: Ray) -> Option<Hit> {
+        // Everything without its part along the axis
+        let o = ray.origin - self.base;
+        let o_perp = o - dot(o, self.axis) * self.axis;
+        let d_perp = ray.direction - dot(ray.direction, self.axis) * self.axis;
+
+        let (t0, t1) = quadratic(dot(d_perp, d_perp), 2. * dot(o_perp, d_perp), dot(o_perp, o_perp) - self.radius * self.radius)?;
+        [t0, t1].iter().filter(|&&t| t > EPS).filter_map(|&t| {
+            let point = ray.eval(t);
+            let h = dot(point - self.base, self.axis);
+            if h < 0. || h > self.height { return None; }
+
+            let radial = point - self.base - h * self.axis;
+            Some(Hit { dist: t, point, norm: radial / self.radius, uv: (turn(radial, self.axis), h / self.height) })
+        }).next()
+    }
+}
+
+impl Shape for Cylinder {
+    fn intersect(&self, ray: Ray) -> Option<Hit> {
+        let [bottom, top] = self.caps();
+        nearer(self.side(ray), nearer(bottom.intersect(ray), top.intersect(ray)))
+    }
+}
+
+impl Surface for Cylinder {
+    fn area(&self) -> Float {
+        2. * PI * self.radius * (self.height + self.radius)
+    }
+
+    fn sample(&self, u1: Float, u2: Float) -> (Vec3, Vec3) {
+        let cap = self.radius * self.radius;
+        match pick(u1, &[2. * self.radius * self.height, cap, cap]) {
+            (0, u1) => {
+                let radial = disk_point(1., u2, self.axis);
+                (self.base + u1 * self.height * self.axis + self.radius * radial, radial)
+            },
+            (which, u1) => self.caps()[which - 1].sample(u1, u2),
+        }
+    }
+
+    fn bounds(&self) -> (Vec3, Vec3) {
+        let [bottom, top] = self.caps();
+        union(bottom.bounds(), top.bounds())
+    }
+}
+
+
+/// A cone with its base closed, pointing from base along the axis to the tip; uv on the side is
+/// the angle round and the fraction of the height, on the base as for Disk
+#[derive(Copy, Clone)]
+pub struct Cone {
+    pub base: Vec3, // Centre of the base
+    pub axis: Vec3,
+    pub radius: Float,
+    pub height: Float,
+}
+
+impl Cone {
+    pub fn new(base: Vec3, axis: Vec3, radius: Float, height: Float) -> Cone {
+        Cone { base, axis: axis.normalise(), radius, height }
+    }
+
+    fn cap(&self) -> Disk {
+        Disk::new(self.base, -self.axis, self.radius)
+    }
+
+    fn slant(&self) -> Float {
+        (self.radius * self.radius + self.height * self.height).sqrt()
+    }
+
+    /// Outward normal on the side, given the direction out from the axis
+    fn side_normal(&self, out: Vec3) -> Vec3 {
+        (1. / self.slant()) * (self.height * out + self.radius * self.axis)
+    }
+
+    fn side(&self, ray: Ray) -> Option<Hit> {
+        // From the tip, the radius is k times the distance back down the axis
+        let tip = self.base + self.height * self.axis;
+        let k2 = (self.radius / self.height) * (self.radius / self.height);
+        let o = ray.origin - tip;
+        let (o_a, d_a) = (dot(o, self.axis), dot(ray.direction, self.axis));
+        let o_perp = o - o_a * self.axis;
+        let d_perp = ray.direction - d_a * self.axis;
+
+        let (t0, t1) = quadratic(dot(d_perp, d_perp) - k2 * d_a * d_a,
+                                 2. * (dot(o_perp, d_perp) - k2 * o_a * d_a),
+                                 dot(o_perp, o_perp) - k2 * o_a * o_a)?;
+        [t0, t1].iter().filter(|&&t| t > EPS).filter_map(|&t| {
+            let point = ray.eval(t);
+            let h = dot(point - self.base, self.axis);
+            // Also rules out the mirror image cone beyond the tip
+            if h < 0. || h > self.height { return None; }
+
+            let radial = point - self.base - h * self.axis;
+            if radial.norm() < EPS { return None; }
+            Some(Hit { dist: t, point, norm: self.side_normal(radial.normalise()), uv: (turn(radial, self.axis), h / self.height) })
+        }).next()
+    }
+}
+
+impl Shape for Cone {
+    fn intersect(&self, ray: Ray) -> Option<Hit> {
+        nearer(self.side(ray), self.cap().intersect(ray))
+    }
+}
+
+impl Surface for Cone {
+    fn area(&self) -> Float {
+        PI * self.radius * (self.slant() + self.radius)
+    }
+
+    fn sample(&self, u1: Float, u2: Float) -> (Vec3, Vec3) {
+        match pick(u1, &[self.slant(), self.radius]) {
+            (0, u1) => {
+                // The circles grow linearly away from the tip, so their area goes with the square
+                let s = u1.sqrt();
+                let out = disk_point(1., u2, self.axis);
+                (self.base + (1. - s) * self.height * self.axis + s * self.radius * out, self.side_normal(out))
+            },
+            (_, u1) => self.cap().sample(u1, u2),
+        }
+    }
+
+    fn bounds(&self) -> (Vec3, Vec3) {
+        let tip = self.base + self.height * self.axis;
+        union(self.cap().bounds(), (tip, tip))
+    }
+}
+
+
+/// A parallelogram from a corner along two edges; the normal is edge_u cross edge_v and uv how
+/// far along each edge
+#[derive(Copy, Clone)]
+pub struct Quad {
+    pub corner: Vec3,
+    pub edge_u: Vec3,
+    pub edge_v: Vec3,
+    pub normal: Vec3,
+}
+
+impl Quad {
+    pub fn new(corner: Vec3, edge_u: Vec3, edge_v: Vec3) -> Quad {
+        Quad { corner, edge_u, edge_v, normal: cross(edge_u, edge_v).normalise() }
+    }
+}
+
+impl Shape for Quad {
+    fn intersect(&self, ray: Ray) -> Option<Hit> {
+        let dist = plane_dist(ray, self.corner, self.normal)?;
+        let point = ray.eval(dist);
+
+        // Coordinates along the edges, which needn't be perpendicular
+        let w = cross(self.edge_u, self.edge_v);
+        let w = (1. / dot(w, w)) * w;
+        let offset = point - self.corner;
+        let u = dot(w, cross(offset, self.edge_v));
+        let v = dot(w, cross(self.edge_u, offset));
+        if u < 0. || u > 1. || v < 0. || v > 1. { return None; }
+
+        Some(Hit { dist, point, norm: self.normal, uv: (u, v) })
+    }
+}
+
+impl Surface for Quad {
+    fn area(&self) -> Float {
+        cross(self.edge_u, self.edge_v).norm()
+    }
+
+    fn sample(&self, u1: Float, u2: Float) -> (Vec3, Vec3) {
+        (self.corner + u1 * self.edge_u + u2 * self.edge_v, self.normal)
+    }
+
+    fn bounds(&self) -> (Vec3, Vec3) {
+        let corners = [self.corner, self.corner + self.edge_u, self.corner + self.edge_v, self.corner + self.edge_u + self.edge_v];
+        corners.iter().fold((corners[0], corners[0]), |bounds, &p| union(bounds, (p, p)))
+    }
+
+    fn flat(&self) -> Option<Vec3> { Some(self.normal) }
+}
+
+
+/// An axis-aligned box, hit by slabs; uv is the position across the face, along the next two axes
+/// round (y then z on the x faces, z then x on the y faces, x then y on the z faces)
+#[derive(Copy, Clone)]
+pub struct AABox {
+    pub lo: Vec3,
+    pub hi: Vec3,
+}
+
+impl AABox {
+    pub fn new(lo: Vec3, hi: Vec3) -> AABox {
+        AABox { lo, hi }
+    }
+
+    fn axis(v: Vec3, i: usize) -> Float {
+        match i { 0 => v.x, 1 => v.y, _ => v.z }
+    }
+
+    fn unit(i: usize) -> Vec3 {
+        match i { 0 => Vec3::X, 1 => Vec3::Y, _ => Vec3::Z }
+    }
+
+    /// Which axis a face is across (the normal's), its side (+-1) and where across it the point is
+    fn face_hit(&self, point: Vec3, axis: usize, side: Float, dist: Float) -> Hit {
+        let size = self.hi - self.lo;
+        let local = point - self.lo;
+        let (i, j) = ((axis + 1) % 3, (axis + 2) % 3);
+        Hit {
+            dist,
+            point,
+            norm: side * AABox::unit(axis),
+            uv: (AABox::axis(local, i) / AABox::axis(size, i), AABox::axis(local, j) / AABox::axis(size, j)),
+        }
+    }
+}
+
+impl Shape for AABox {
+    fn intersect(&self, ray: Ray) -> Option<Hit> {
+        // Distances into and out of the slabs, and across which axis
+        let (mut enter, mut enter_axis) = (-Float::INFINITY, 0);
+        let (mut leave, mut leave_axis) = (Float::INFINITY, 0);
+
+        for i in 0..3 {
+            let (o, d) = (AABox::axis(ray.origin, i), AABox::axis(ray.direction, i));
+            let (lo, hi) = (AABox::axis(self.lo, i), AABox::axis(self.hi, i));
+            if d.abs() < EPS * EPS {
+                if o < lo || o > hi { return None; }
+                continue;
+            }
+
+            let (t0, t1) = ((lo - o) / d, (hi - o) / d);
+            let (near, far) = (t0.min(t1), t0.max(t1));
+            if near > enter { enter = near; enter_axis = i; }
+            if far < leave { leave = far; leave_axis = i; }
+        }
+
+        if enter > leave || leave <= EPS { return None; }
+
+        // The normal points out of the box whether the ray starts outside or in, like a sphere's
+        let (dist, axis) = if enter > EPS { (enter, enter_axis) } else { (leave, leave_axis) };
+        let point = ray.eval(dist);
+        let side = if dist == enter { -AABox::axis(ray.direction, axis).signum() } else { AABox::axis(ray.direction, axis).signum() };
+        Some(self.face_hit(point, axis, side, dist))
+    }
+}
+
+impl Surface for AABox {
+    fn area(&self) -> Float {
+        let s = self.hi - self.lo;
+        2. * (s.x * s.y + s.y * s.z + s.z * s.x)
+    }
+
+    fn sample(&self, u1: Float, u2: Float) -> (Vec3, Vec3) {
+        let s = self.hi - self.lo;
+        let faces = [s.y * s.z, s.y * s.z, s.z * s.x, s.z * s.x, s.x * s.y, s.x * s.y];
+        let (face, u1) = pick(u1, &faces);
+        let (axis, side) = (face / 2, if face % 2 == 0 { -1. } else { 1. });
+
+        let (i, j) = ((axis + 1) % 3, (axis + 2) % 3);
+        let base = if side < 0. { self.lo } else { self.lo + AABox::axis(s, axis) * AABox::unit(axis) };
+        let point = base + u1 * AABox::axis(s, i) * AABox::unit(i) + u2 * AABox::axis(s, j) * AABox::unit(j);
+        (point, side * AABox::unit(axis))
+    }
+
+    fn bounds(&self) -> (Vec3, Vec3) {
+        (self.lo, self.hi)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Straight down onto the z = 0 plane
+    fn down(x: Float, y: Float) -> Ray {
+        Ray::new(Vec3::new(x, y, 1.), -Vec3::Z)
+    }
+
+    #[test]
+    fn shared_edge_hits_one_triangle() {
+        // A unit square split along its diagonal, both halves wound the same way
+        let (a, b, c, d) = (Vec3::ZERO, Vec3::X, Vec3::new(1., 1., 0.), Vec3::Y);
+        let (lower, upper) = (Triangle::new(a, b, c), Triangle::new(a, c, d));
+
+        for &t in [0.125, 0.25, 0.5, 0.75].iter() {
+            let hits = [&lower, &upper].iter().filter(|triangle| triangle.intersect(down(t, t)).is_some()).count();
+            assert_eq!(hits, 1);
+        }
+    }
+
+    #[test]
+    fn vertex_uvs() {
+        let triangle = Triangle::new(Vec3::ZERO, Vec3::X, Vec3::new(1., 1., 0.));
+
+        for &((x, y), uv) in [((0., 0.), (0., 0.)), ((1., 0.), (1., 0.)), ((1., 1.), (0., 1.))].iter() {
+            let hit = triangle.intersect(down(x, y)).unwrap();
+            assert!((hit.uv.0 - uv.0).abs() < EPS && (hit.uv.1 - uv.1).abs() < EPS);
+        }
+    }
+}
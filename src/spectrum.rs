@@ -0,0 +1,154 @@
+/* Spectral rendering: sampling wavelengths, turning RGB into spectra and spectra back into RGB.
+   A path carries three wavelengths (a hero and two evenly spaced companions) in the slots
+   of a Colour, so the tracing code can multiply them around like normal colours */
+#![allow(dead_code, unused_imports)]
+use super::vector::*;
+use super::colour::*;
+
+pub const LAMBDA_MIN: Float = 360.;
+pub const LAMBDA_MAX: Float = 830.;
+const LAMBDA_RANGE: Float = LAMBDA_MAX - LAMBDA_MIN;
+
+/// Number of wavelengths carried by each path (one per Colour slot)
+pub const WAVELENGTHS: usize = 3;
+
+/// Piecewise Gaussian used in the CIE fit
+fn gaussian(lambda: Float, mu: Float, sigma1: Float, sigma2: Float) -> Float {
+    let t = (lambda - mu) / if lambda < mu { sigma1 } else { sigma2 };
+    (-0.5 * t * t).exp()
+}
+
+/// CIE 1931 colour matching functions; the multi-lobe fit from Wyman, Sloan and Shirley
+pub fn cie_xyz(lambda: Float) -> Vec3 {
+    Vec3::new(
+        1.056 * gaussian(lambda, 599.8, 37.9, 31.0) + 0.362 * gaussian(lambda, 442.0, 16.0, 26.7)
+            - 0.065 * gaussian(lambda, 501.1, 20.4, 26.2),
+        0.821 * gaussian(lambda, 568.8, 46.9, 40.5) + 0.286 * gaussian(lambda, 530.9, 16.3, 31.1),
+        1.217 * gaussian(lambda, 437.0, 11.8, 36.0) + 0.681 * gaussian(lambda, 459.0, 26.0, 13.8),
+    )
+}
+
+/// CIE XYZ to linear sRGB
+pub fn xyz_to_rgb(xyz: Vec3) -> Colour {
+    Colour::new(
+        3.2406 * xyz.x - 1.5372 * xyz.y - 0.4986 * xyz.z,
+        -0.9689 * xyz.x + 1.8758 * xyz.y + 0.0415 * xyz.z,
+        0.0557 * xyz.x - 0.2040 * xyz.y + 1.0570 * xyz.z,
+    )
+}
+
+fn smoothstep(low: Float, high: Float, x: Float) -> Float {
+    let t = ((x - low) / (high - low)).clamp(0., 1.);
+    t * t * (3. - 2. * t)
+}
+
+/// Smooth red, green and blue spectra used for upsampling; they sum to one everywhere
+fn basis(lambda: Float) -> Vec3 {
+    let blue = 1. - smoothstep(450., 520., lambda);
+    let red = smoothstep(560., 610., lambda);
+    Vec3::new(red, 1. - red - blue, blue)
+}
+
+/// Everything needed to go between RGB and spectra, computed once
+#[derive(Clone)]
+pub struct Spectral {
+    rgb_to_basis: [Vec3; 3], // Rows of the inverse of the basis' RGB colours
+    white: Colour, // One over the RGB of a flat spectrum, so white stays white
+}
+
+impl Spectral {
+    pub fn new() -> Spectral {
+        // Integrate against the matching functions in 1nm steps
+        let integrate = |f: &dyn Fn(Float) -> Float| {
+            let mut xyz = Vec3::ZERO;
+            let mut lambda = LAMBDA_MIN;
+            while lambda <= LAMBDA_MAX {
+                xyz += f(lambda) * cie_xyz(lambda);
+                lambda += 1.;
+            }
+            xyz_to_rgb(xyz)
+        };
+
+        let flat = integrate(&|_| 1.);
+        let white = Colour::new(1. / flat.x, 1. / flat.y, 1. / flat.z);
+
+        // The RGB colour each basis spectrum turns into
+        let red = white * integrate(&|lambda| basis(lambda).x);
+        let green = white * integrate(&|lambda| basis(lambda).y);
+        let blue = white * integrate(&|lambda| basis(lambda).z);
+
+        // Cramer's rule for the basis weights giving a colour
+        let det = dot(red, cross(green, blue));
+
+        Spectral {
+            rgb_to_basis: [
+                cross(green, blue) / det,
+                cross(blue, red) / det,
+                cross(red, green) / det,
+            ],
+            white,
+        }
+    }
+
+    /// Pick the hero wavelength uniformly; the companions are spaced evenly from it
+    pub fn sample_wavelengths(&self, u: Float) -> Wavelengths {
+        let mut lambda = [0.; WAVELENGTHS];
+        let mut upsample = [Vec3::ZERO; WAVELENGTHS];
+        let mut to_rgb = [Colour::BLACK; WAVELENGTHS];
+
+        for i in 0..WAVELENGTHS {
+            let offset = (u + i as Float / WAVELENGTHS as Float).fract();
+            lambda[i] = LAMBDA_MIN + offset * LAMBDA_RANGE;
+
+            let b = basis(lambda[i]);
+            upsample[i] = b.x * self.rgb_to_basis[0] + b.y * self.rgb_to_basis[1] + b.z * self.rgb_to_basis[2];
+
+            // Monte Carlo weight; every wavelength has pdf 1 / range
+            to_rgb[i] = (LAMBDA_RANGE / WAVELENGTHS as Float) * (self.white * xyz_to_rgb(cie_xyz(lambda[i])));
+        }
+
+        Wavelengths {
+            lambda: Vec3::new(lambda[0], lambda[1], lambda[2]),
+            upsample,
+            to_rgb,
+            secondary_terminated: false,
+        }
+    }
+}
+
+/// The wavelengths a path is carrying; the hero is in x
+#[derive(Copy, Clone)]
+pub struct Wavelengths {
+    pub lambda: Vec3,
+    upsample: [Vec3; WAVELENGTHS], // Spectrum value of an RGB colour at each wavelength is a dot product
+    to_rgb: [Colour; WAVELENGTHS],
+    pub secondary_terminated: bool, // After dispersion only the hero is followed
+}
+
+impl Wavelengths {
+    fn spectrum(&self, colour: Colour) -> Vec3 {
+        Vec3::new(dot(self.upsample[0], colour), dot(self.upsample[1], colour), dot(self.upsample[2], colour))
+    }
+
+    /// Spectrum of an albedo; kept between 0 and 1 so surfaces don't gain energy
+    pub fn reflectance(&self, colour: Colour) -> Vec3 {
+        let s = self.spectrum(colour);
+        Vec3::new(s.x.clamp(0., 1.), s.y.clamp(0., 1.), s.z.clamp(0., 1.))
+    }
+
+    /// Spectrum of an emitter; can be as bright as needed
+    pub fn illuminant(&self, colour: Colour) -> Vec3 {
+        let s = self.spectrum(colour);
+        Vec3::new(s.x.max(0.), s.y.max(0.), s.z.max(0.))
+    }
+
+    /// The same wavelengths once only the hero is being followed
+    pub fn terminate_secondary(self) -> Wavelengths {
+        Wavelengths { secondary_terminated: true, ..self }
+    }
+
+    /// Turn the radiance carried at each wavelength back into RGB
+    pub fn to_rgb(&self, values: Vec3) -> Colour {
+        values.x * self.to_rgb[0] + values.y * self.to_rgb[1] + values.z * self.to_rgb[2]
+    }
+}
@@ -0,0 +1,240 @@
+/* Rigid transforms (plus uniform scale) and how they change over time for motion blur */
+#![allow(dead_code)]
+use super::vector::*;
+
+/// A unit quaternion for rotations; w is the real part
+#[derive(Copy, Clone)]
+pub struct Quaternion {
+    pub w: Float,
+    pub v: Vec3,
+}
+
+impl Quaternion {
+    pub const IDENTITY: Quaternion = Quaternion { w: 1., v: Vec3::ZERO };
+
+    /// Rotation by an angle (radians, anticlockwise looking down the axis) about an axis
+    pub fn from_axis_angle(axis: Vec3, angle: Float) -> Quaternion {
+        Quaternion { w: (angle / 2.).cos(), v: (angle / 2.).sin() * axis.normalise() }
+    }
+
+    pub fn conjugate(self) -> Quaternion {
+        Quaternion { w: self.w, v: -self.v }
+    }
+
+    pub fn dot(self, other: Quaternion) -> Float {
+        self.w * other.w + dot(self.v, other.v)
+    }
+
+    fn scale(self, s: Float) -> Quaternion {
+        Quaternion { w: s * self.w, v: s * self.v }
+    }
+
+    fn add(self, other: Quaternion) -> Quaternion {
+        Quaternion { w: self.w + other.w, v: self.v + other.v }
+    }
+
+    pub fn normalise(self) -> Quaternion {
+        self.scale(1. / self.dot(self).sqrt())
+    }
+
+    /// Hamilton product; rotating by the result is other then self
+    pub fn mul(self, other: Quaternion) -> Quaternion {
+        Quaternion {
+            w: self.w * other.w - dot(self.v, other.v),
+            v: self.w * other.v + other.w * self.v + cross(self.v, other.v),
+        }
+    }
+
+    pub fn rotate(self, u: Vec3) -> Vec3 {
+        // u + 2w(v x u) + 2v x (v x u)
+        let t = 2. * cross(self.v, u);
+        u + self.w * t + cross(self.v, t)
+    }
+
+    /// Spherical interpolation along the shorter way round
+    pub fn slerp(self, other: Quaternion, t: Float) -> Quaternion {
+        let mut cos = self.dot(other);
+        let mut other = other;
+        if cos < 0. {
+            cos = -cos;
+            other = other.scale(-1.);
+        }
+
+        // Nearly the same rotation; a straight line is fine and avoids dividing by ~0
+        if cos > 1. - EPS {
+            return self.scale(1. - t).add(other.scale(t)).normalise();
+        }
+
+        let angle = cos.acos();
+        let sin = angle.sin();
+        self.scale(((1. - t) * angle).sin() / sin).add(other.scale((t * angle).sin() / sin))
+    }
+}
+
+/// Scale, then rotate, then translate
+#[derive(Copy, Clone)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Quaternion,
+    pub scale: Float,
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform { translation: Vec3::ZERO, rotation: Quaternion::IDENTITY, scale: 1. };
+
+    pub fn translation(translation: Vec3) -> Transform {
+        Transform { translation, ..Transform::IDENTITY }
+    }
+
+    pub fn point(&self, p: Vec3) -> Vec3 {
+        self.rotation.rotate(self.scale * p) + self.translation
+    }
+
+    pub fn vector(&self, u: Vec3) -> Vec3 {
+        self.rotation.rotate(self.scale * u)
+    }
+
+    /// Normals only rotate, since the scale is uniform
+    pub fn normal(&self, n: Vec3) -> Vec3 {
+        self.rotation.rotate(n)
+    }
+
+    pub fn inverse_point(&self, p: Vec3) -> Vec3 {
+        (1. / self.scale) * self.rotation.conjugate().rotate(p - self.translation)
+    }
+
+    pub fn inverse_vector(&self, u: Vec3) -> Vec3 {
+        (1. / self.scale) * self.rotation.conjugate().rotate(u)
+    }
+
+    pub fn lerp(&self, other: &Transform, t: Float) -> Transform {
+        Transform {
+            translation: self.translation + t * (other.translation - self.translation),
+            rotation: self.rotation.slerp(other.rotation, t),
+            scale: self.scale + t * (other.scale - self.scale),
+        }
+    }
+}
+
+/// Any affine transform as a 4x4 matrix, m[row][column]; for instances, which can also shear and
+/// scale unevenly unlike Transform
+#[derive(Copy, Clone)]
+pub struct Matrix4 {
+    pub m: [[Float; 4]; 4],
+}
+
+impl Matrix4 {
+    pub const IDENTITY: Matrix4 = Matrix4 { m: [[1., 0., 0., 0.], [0., 1., 0., 0.], [0., 0., 1., 0.], [0., 0., 0., 1.]] };
+
+    pub fn translation(t: Vec3) -> Matrix4 {
+        Matrix4 { m: [[1., 0., 0., t.x], [0., 1., 0., t.y], [0., 0., 1., t.z], [0., 0., 0., 1.]] }
+    }
+
+    pub fn scale(s: Vec3) -> Matrix4 {
+        Matrix4 { m: [[s.x, 0., 0., 0.], [0., s.y, 0., 0.], [0., 0., s.z, 0.], [0., 0., 0., 1.]] }
+    }
+
+    /// Radians anticlockwise looking down the axis, as Quaternion::from_axis_angle
+    pub fn rotation(axis: Vec3, angle: Float) -> Matrix4 {
+        Matrix4::from_transform(&Transform { rotation: Quaternion::from_axis_angle(axis, angle), ..Transform::IDENTITY })
+    }
+
+    /// Columns from where the basis vectors go; e.g. the up, side and back of a cuboid
+    pub fn from_columns(x: Vec3, y: Vec3, z: Vec3, translation: Vec3) -> Matrix4 {
+        Matrix4 { m: [[x.x, y.x, z.x, translation.x], [x.y, y.y, z.y, translation.y], [x.z, y.z, z.z, translation.z], [0., 0., 0., 1.]] }
+    }
+
+    pub fn from_transform(t: &Transform) -> Matrix4 {
+        Matrix4::from_columns(t.vector(Vec3::X), t.vector(Vec3::Y), t.vector(Vec3::Z), t.translation)
+    }
+
+    /// Doing other then self
+    pub fn mul(&self, other: &Matrix4) -> Matrix4 {
+        let mut m = [[0.; 4]; 4];
+        for (row, self_row) in m.iter_mut().zip(self.m.iter()) {
+            for (j, entry) in row.iter_mut().enumerate() {
+                *entry = self_row.iter().zip(other.m.iter()).map(|(a, other_row)| a * other_row[j]).sum();
+            }
+        }
+        Matrix4 { m }
+    }
+
+    pub fn transpose(&self) -> Matrix4 {
+        let mut m = self.m;
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, entry) in row.iter_mut().enumerate() {
+                *entry = self.m[j][i];
+            }
+        }
+        Matrix4 { m }
+    }
+
+    /// Gauss-Jordan with partial pivoting; None if the matrix squashes space flat
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let mut a = self.m;
+        let mut inv = Matrix4::IDENTITY.m;
+
+        for column in 0..4 {
+            let pivot = (column..4).max_by(|&i, &j| a[i][column].abs().partial_cmp(&a[j][column].abs()).unwrap()).unwrap();
+            if a[pivot][column].abs() < EPS * EPS { return None; }
+            a.swap(column, pivot);
+            inv.swap(column, pivot);
+
+            let scale = 1. / a[column][column];
+            for j in 0..4 {
+                a[column][j] *= scale;
+                inv[column][j] *= scale;
+            }
+
+            for i in 0..4 {
+                if i == column { continue; }
+                let factor = a[i][column];
+                for j in 0..4 {
+                    a[i][j] -= factor * a[column][j];
+                    inv[i][j] -= factor * inv[column][j];
+                }
+            }
+        }
+        Some(Matrix4 { m: inv })
+    }
+
+    pub fn point(&self, p: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+                  m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+                  m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3])
+    }
+
+    pub fn vector(&self, u: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(m[0][0] * u.x + m[0][1] * u.y + m[0][2] * u.z,
+                  m[1][0] * u.x + m[1][1] * u.y + m[1][2] * u.z,
+                  m[2][0] * u.x + m[2][1] * u.y + m[2][2] * u.z)
+    }
+}
+
+/// How a transform changes with time
+pub enum Motion {
+    Linear { start: Transform, end: Transform }, // From time 0 to time 1, held outside that
+    Keyframed(Vec<(Float, Transform)>), // Times increasing; interpolated between, held before and after; none is the identity
+}
+
+impl Motion {
+    pub fn at(&self, time: Float) -> Transform {
+        match self {
+            Motion::Linear { start, end } => start.lerp(end, time.clamp(0., 1.)),
+            Motion::Keyframed(keys) => {
+                let (first, last) = match (keys.first(), keys.last()) {
+                    (Some(first), Some(last)) => (first, last),
+                    _ => return Transform::IDENTITY,
+                };
+                if time <= first.0 { return first.1 };
+                if time >= last.0 { return last.1 };
+
+                let i = keys.windows(2).position(|pair| time <= pair[1].0).unwrap_or(keys.len() - 2);
+                let (t0, t1) = (keys[i].0, keys[i + 1].0);
+                keys[i].1.lerp(&keys[i + 1].1, (time - t0) / (t1 - t0).max(EPS))
+            },
+        }
+    }
+}
@@ -2,19 +2,20 @@
 #![allow(dead_code, unused_imports)]
 
 use std::ops::{Add, Sub, Mul, Div, AddAssign, Neg, Index};
+use super::sampler::Sampler;
 pub type Float = f32;
 pub const PI: Float = 3.14159;
 pub const EPS: Float = 1e-5;
 
-/// Wrapper for random float function
-pub fn random_float() -> Float {
-    rand::random::<Float>()
+/// Wrapper for random float function; the numbers come from the path's sampler
+pub fn random_float(sampler: &mut dyn Sampler) -> Float {
+    sampler.next_float()
 }
 
 /// Random vector on unit hemisphere
-pub fn random_vector() -> Vec3 {
-    let u1 = random_float();
-    let u2 = random_float();
+pub fn random_vector(sampler: &mut dyn Sampler) -> Vec3 {
+    let u1 = random_float(sampler);
+    let u2 = random_float(sampler);
 
     let r = (1.-u1*u1).sqrt();
     let phi = 2.*PI*u2;
@@ -23,15 +24,15 @@ pub fn random_vector() -> Vec3 {
 }
 
 /// Random vector in the hemisphere defined by u
-pub fn random_hemisphere(u: Vec3) -> Vec3 {
-    let v = random_vector();
+pub fn random_hemisphere(u: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+    let v = random_vector(sampler);
     dot(u, v).signum() * v
 }
 
 /// Cosine weighted sampling on the unit hemisphere upwards
-pub fn random_cosine() -> Vec3 {
-    let u1 = random_float();
-    let theta = 2. * PI * random_float();
+pub fn random_cosine(sampler: &mut dyn Sampler) -> Vec3 {
+    let u1 = random_float(sampler);
+    let theta = 2. * PI * random_float(sampler);
 
     let r = u1.sqrt();
 
@@ -55,9 +56,9 @@ pub fn onb(u: Vec3) -> (Vec3, Vec3) {
 }
 
 /// Cosine weighted sampling on the hemisphere defined by u.
-pub fn random_hemisphere_cosine(u: Vec3) -> Vec3 {
+pub fn random_hemisphere_cosine(u: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
     let (v, w) = onb(u);
-    let r = random_cosine();
+    let r = random_cosine(sampler);
     
     // The cosine weighted random vector has positive z component;
     // we want the transformed ray to be in the u direction, so
@@ -196,12 +197,18 @@ impl Div<Float> for Vec3 {
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
-    // Time, other clever factors etc
+    pub time: Float, // When in the shutter interval the ray was sent; moving things are where they were then
+    // Other clever factors etc
 }
 
 impl Ray {
     pub fn new(origin: Vec3, direction: Vec3) -> Ray {
-        Ray {origin: origin, direction: direction}
+        Ray {origin, direction, time: 0.}
+    }
+
+    /// A ray carrying on from this one, at the same time
+    pub fn spawn(self, origin: Vec3, direction: Vec3) -> Ray {
+        Ray {origin, direction, time: self.time}
     }
 
     pub fn eval(self, dist: Float) -> Vec3 {